@@ -0,0 +1,161 @@
+use std::cmp::min;
+use std::mem::take;
+use std::time::{Duration, Instant};
+
+/// Longest time a record waits for its batch to fill up, the batch being sent
+/// on the next record or on flush.
+pub const MAX_BATCH_DELAY: Duration = Duration::from_secs(1);
+
+/// Accumulates records for a single batch API call until one of the service
+/// limits is reached.
+pub struct Batch<T> {
+    items: Vec<T>,
+    size: usize,
+    started: Option<Instant>,
+    max_items: usize,
+    max_bytes: usize,
+}
+
+impl<T> Batch<T> {
+    pub fn new(max_items: usize, max_bytes: usize) -> Self {
+        Self {
+            items: Vec::new(),
+            size: 0,
+            started: None,
+            max_items,
+            max_bytes,
+        }
+    }
+
+    pub fn fits(&self, size: usize) -> bool {
+        self.items.len() < self.max_items && self.size + size <= self.max_bytes
+    }
+
+    pub fn push(&mut self, item: T, size: usize) {
+        self.started.get_or_insert_with(Instant::now);
+        self.items.push(item);
+        self.size += size;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Whether the oldest record has been waiting longer than the given delay.
+    pub fn is_expired(&self, max_delay: Duration) -> bool {
+        self.started
+            .is_some_and(|started| started.elapsed() >= max_delay)
+    }
+
+    pub fn take(&mut self) -> Vec<T> {
+        self.size = 0;
+        self.started = None;
+        take(&mut self.items)
+    }
+}
+
+/// Exponential backoff between retries of the failed records of a batch.
+pub fn backoff(attempt: u32) -> Duration {
+    let millis = 100u64.saturating_mul(1 << min(attempt, 16));
+    min(Duration::from_millis(millis), Duration::from_secs(10))
+}
+
+/// Serves the given JSON responses in turn, as an AWS endpoint for the tests,
+/// and records the JSON body of each request.
+#[cfg(test)]
+pub async fn serve_responses(
+    responses: Vec<&'static str>,
+) -> (
+    String,
+    std::sync::Arc<std::sync::Mutex<Vec<serde_json::Value>>>,
+) {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let received = requests.clone();
+    tokio::spawn(async move {
+        for response in responses {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0u8; length];
+            stream.read_exact(&mut body).await.unwrap();
+            received
+                .lock()
+                .unwrap()
+                .push(serde_json::from_slice(&body).unwrap());
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/x-amz-json-1.1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.len(),
+                response
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    (format!("http://{}", addr), requests)
+}
+
+/// A writer expecting exactly the given messages in order, then a close.
+#[cfg(test)]
+pub fn expect_messages(messages: &[&'static [u8]]) -> crate::writer::MockAsyncLogWriter {
+    let mut mock = crate::writer::MockAsyncLogWriter::new();
+    let mut seq = mockall::Sequence::new();
+    for &message in messages {
+        mock.expect_write_logs()
+            .withf(move |record| record.message == message)
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+    }
+    mock.expect_close()
+        .times(1)
+        .in_sequence(&mut seq)
+        .returning(|| Ok(()));
+    mock
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn respect_limits() {
+        let mut batch = Batch::new(2, 10);
+        assert!(batch.is_empty());
+        assert!(!batch.is_expired(Duration::ZERO));
+        assert!(!batch.fits(11));
+
+        batch.push("foo", 6);
+        assert!(batch.fits(4));
+        assert!(!batch.fits(5));
+        assert!(batch.is_expired(Duration::ZERO));
+
+        batch.push("bar", 1);
+        assert!(!batch.fits(1));
+
+        assert_eq!(batch.take(), vec!["foo", "bar"]);
+        assert!(batch.is_empty());
+        assert!(batch.fits(10));
+    }
+
+    #[test]
+    fn cap_backoff() {
+        assert_eq!(backoff(0), Duration::from_millis(100));
+        assert_eq!(backoff(3), Duration::from_millis(800));
+        assert_eq!(backoff(100), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,237 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// Types of the sinks of the configuration file, i.e. the flags enabling them.
+const SINK_TYPES: [&str; 5] = ["aws", "kinesis", "firehose", "newrelic", "journald"];
+
+/// The configuration file as command line arguments.
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    /// Top-level options by key, e.g. `("filter", ["--filter", "exclude=x"])`,
+    /// to be overridden by the command line.
+    pub args: Vec<(String, Vec<OsString>)>,
+    pub input_file: Option<PathBuf>,
+    /// Name and options of each sink, e.g. `["--aws", "--aws-region", "eu-west-1"]`.
+    pub sinks: Vec<(String, Vec<OsString>)>,
+}
+
+/// Reads a TOML file whose keys are the long options without the leading
+/// dashes. Sinks are tables under `sinks` with a `type` and the options of
+/// that type without its prefix, e.g. `region` for `--aws-region`.
+pub fn load(path: &Path) -> Result<Config, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    parse(&text, |var| std::env::var(var).ok())
+        .map_err(|e| format!("invalid config {}: {}", path.display(), e))
+}
+
+fn parse(text: &str, env: impl Fn(&str) -> Option<String>) -> Result<Config, String> {
+    let mut table: Table = text
+        .parse()
+        .map_err(|e: toml::de::Error| e.message().to_string())?;
+    let mut config = Config::default();
+
+    if let Some(sinks) = table.remove("sinks") {
+        let Value::Table(sinks) = sinks else {
+            return Err("sinks must be a table".to_string());
+        };
+        for (name, sink) in sinks {
+            let Value::Table(mut sink) = sink else {
+                return Err(format!("sink {} must be a table", name));
+            };
+            let kind = match sink.remove("type") {
+                Some(Value::String(kind)) if SINK_TYPES.contains(&kind.as_str()) => kind,
+                _ => {
+                    return Err(format!(
+                        "sink {} needs a type among {}",
+                        name,
+                        SINK_TYPES.join(", ")
+                    ))
+                }
+            };
+            let mut args = vec![OsString::from(format!("--{}", kind))];
+            for (key, value) in &sink {
+                push_arg(&mut args, &format!("{}-{}", kind, key), value, &env)?;
+            }
+            config.sinks.push((name, args));
+        }
+    }
+
+    if let Some(input_file) = table.remove("input-file") {
+        let Value::String(input_file) = input_file else {
+            return Err("input-file must be a string".to_string());
+        };
+        config.input_file = Some(interpolate(&input_file, &env)?.into());
+    }
+    for (key, value) in &table {
+        if key == "config" {
+            return Err("config can't be nested".to_string());
+        }
+        let mut args = Vec::new();
+        push_arg(&mut args, key, value, &env)?;
+        config.args.push((key.clone(), args));
+    }
+    Ok(config)
+}
+
+/// Appends `--key value`, once per value of arrays, or only `--key` for true.
+fn push_arg(
+    args: &mut Vec<OsString>,
+    key: &str,
+    value: &Value,
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    let value = match value {
+        Value::Boolean(true) => {
+            args.push(format!("--{}", key).into());
+            return Ok(());
+        }
+        Value::Boolean(false) => return Ok(()),
+        Value::Array(values) => {
+            for value in values {
+                if matches!(value, Value::Array(_) | Value::Table(_) | Value::Boolean(_)) {
+                    return Err(format!("{} must be an array of strings or numbers", key));
+                }
+                push_arg(args, key, value, env)?;
+            }
+            return Ok(());
+        }
+        Value::Table(_) => return Err(format!("{} can't be a table", key)),
+        Value::String(value) => interpolate(value, env)?,
+        Value::Integer(value) => value.to_string(),
+        Value::Float(value) => value.to_string(),
+        Value::Datetime(value) => value.to_string(),
+    };
+    args.push(format!("--{}", key).into());
+    args.push(value.into());
+    Ok(())
+}
+
+/// Replaces `${VAR}` and `${VAR:-default}` with the environment variables,
+/// and `$$` with `$`.
+fn interpolate(text: &str, env: &impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unterminated ${{ in {}", text))?;
+            let (var, default) = match after[..end].split_once(":-") {
+                Some((var, default)) => (var, Some(default)),
+                None => (&after[..end], None),
+            };
+            match (env(var), default) {
+                (Some(value), _) => result.push_str(&value),
+                (None, Some(default)) => result.push_str(default),
+                (None, None) => return Err(format!("environment variable {} is not set", var)),
+            }
+            rest = &after[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(var: &str) -> Option<String> {
+        (var == "API_KEY").then(|| "secret".to_string())
+    }
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = parse(
+            r#"
+            input-file = "/var/log/${APP:-app}.log"
+            severity-detect = true
+            strip-ansi = false
+            max-retries = 3
+            filter = ["exclude=health", "min-level=info"]
+
+            [sinks.audit]
+            type = "aws"
+            log-group-name = "/audit"
+            region = "eu-west-1"
+
+            [sinks.errors]
+            type = "newrelic"
+            region = "EU"
+            api-key = "${API_KEY}"
+            "#,
+            env,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                args: vec![
+                    (
+                        "filter".to_string(),
+                        args(&["--filter", "exclude=health", "--filter", "min-level=info"])
+                    ),
+                    ("max-retries".to_string(), args(&["--max-retries", "3"])),
+                    ("severity-detect".to_string(), args(&["--severity-detect"])),
+                    ("strip-ansi".to_string(), args(&[])),
+                ],
+                input_file: Some("/var/log/app.log".into()),
+                sinks: vec![
+                    (
+                        "audit".to_string(),
+                        args(&[
+                            "--aws",
+                            "--aws-log-group-name",
+                            "/audit",
+                            "--aws-region",
+                            "eu-west-1"
+                        ])
+                    ),
+                    (
+                        "errors".to_string(),
+                        args(&[
+                            "--newrelic",
+                            "--newrelic-api-key",
+                            "secret",
+                            "--newrelic-region",
+                            "EU"
+                        ])
+                    ),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        assert!(parse("[sinks.x]\nregion = \"EU\"", env).is_err());
+        assert!(parse("[sinks.x]\ntype = \"file\"", env).is_err());
+        assert!(parse("filter = [[\"x\"]]", env).is_err());
+        assert!(parse("key = \"${MISSING}\"", env).is_err());
+        assert!(parse("config = \"other.toml\"", env).is_err());
+        assert!(parse("not toml", env).is_err());
+    }
+
+    #[test]
+    fn interpolate_variables() {
+        assert_eq!(
+            interpolate("a ${API_KEY} $HOME $$ ${X:-b:-c}", &env).unwrap(),
+            "a secret $HOME $ b:-c"
+        );
+        assert!(interpolate("${API_KEY", &env).is_err());
+    }
+}
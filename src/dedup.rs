@@ -0,0 +1,253 @@
+use crate::metrics::metrics;
+use crate::record::LogRecord;
+use crate::timestamp::{HTTPDATE, RFC3339, SYSLOG};
+use crate::writer::AsyncLogWriter;
+use async_trait::async_trait;
+use clap::{Args, ValueEnum};
+use regex::Regex;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant, SystemTime};
+
+static DIGITS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d+").unwrap());
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Normalization {
+    // Numbers such as ids and durations
+    Digits,
+    // Times in the RFC 3339, syslog and HTTP formats
+    Timestamps,
+}
+
+#[derive(Args)]
+pub struct DedupArgs {
+    #[arg(
+        long,
+        help = "Collapse consecutive repeated logs into a \"last message repeated N times\" log"
+    )]
+    dedup: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "PART",
+        requires = "dedup",
+        help = "Ignore the given part of the logs when comparing them"
+    )]
+    dedup_ignore: Vec<Normalization>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "10",
+        requires = "dedup",
+        help = "Report the repetitions at least once per interval"
+    )]
+    dedup_window: u64,
+}
+
+pub struct Deduplicator {
+    ignore: Vec<Normalization>,
+    window: Duration,
+    // normalized message of the last log passed through, and the log itself
+    last: Option<(String, LogRecord)>,
+    repeated: u64,
+    window_start: Instant,
+}
+
+impl Deduplicator {
+    pub fn new(args: &DedupArgs, now: Instant) -> Option<Self> {
+        if !args.dedup {
+            return None;
+        }
+        Some(Self {
+            ignore: args.dedup_ignore.clone(),
+            window: Duration::from_secs(args.dedup_window),
+            last: None,
+            repeated: 0,
+            window_start: now,
+        })
+    }
+
+    fn normalize(&self, record: &LogRecord) -> String {
+        let mut message = String::from_utf8_lossy(&record.message).into_owned();
+        if self.ignore.contains(&Normalization::Timestamps) {
+            for regex in [&RFC3339, &SYSLOG, &HTTPDATE] {
+                message = regex.replace_all(&message, "").into_owned();
+            }
+        }
+        if self.ignore.contains(&Normalization::Digits) {
+            message = DIGITS.replace_all(&message, "0").into_owned();
+        }
+        message
+    }
+
+    /// Whether the record repeats the last one, in which case it's counted
+    /// rather than passed through.
+    pub fn is_repeated(&mut self, record: &LogRecord) -> bool {
+        let normalized = self.normalize(record);
+        if self
+            .last
+            .as_ref()
+            .is_some_and(|(last, _)| *last == normalized)
+        {
+            self.repeated += 1;
+            return true;
+        }
+        self.last = Some((normalized, record.clone()));
+        false
+    }
+
+    /// Record reporting the repetitions of the last log, if the window is
+    /// over or when forced. A new window starts afterwards.
+    pub fn summary(&mut self, now: Instant, force: bool) -> Option<LogRecord> {
+        if self.repeated == 0 || (!force && now.duration_since(self.window_start) < self.window) {
+            return None;
+        }
+        let (_, last) = self.last.as_ref()?;
+        let message = format!("last message repeated {} times\n", self.repeated);
+        let mut record = last.with_message(message.into_bytes());
+        record.timestamp = SystemTime::now();
+        self.repeated = 0;
+        self.window_start = now;
+        Some(record)
+    }
+
+    fn start_window(&mut self, now: Instant) {
+        if self.repeated == 0 {
+            self.window_start = now;
+        }
+    }
+}
+
+/// Collapses consecutive repeated records into a summary of the repetitions.
+pub struct DedupWriter<T: AsyncLogWriter> {
+    inner: T,
+    deduplicator: Deduplicator,
+}
+
+impl<T: AsyncLogWriter> DedupWriter<T> {
+    pub fn new(inner: T, deduplicator: Deduplicator) -> Self {
+        Self {
+            inner,
+            deduplicator,
+        }
+    }
+}
+
+impl<T: AsyncLogWriter + Send> DedupWriter<T> {
+    async fn write_summary(&mut self, force: bool) -> std::io::Result<()> {
+        match self.deduplicator.summary(Instant::now(), force) {
+            Some(summary) => self.inner.write_logs(&summary).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: AsyncLogWriter + Send> AsyncLogWriter for DedupWriter<T> {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let now = Instant::now();
+        self.deduplicator.start_window(now);
+        if self.deduplicator.is_repeated(record) {
+            metrics().deduplicated.inc();
+            return self.write_summary(false).await;
+        }
+        // a different log ends the repetitions
+        self.write_summary(true).await?;
+        self.inner.write_logs(record).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.write_summary(false).await?;
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        self.write_summary(true).await?;
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::MockAsyncLogWriter;
+    use mockall::Sequence;
+
+    fn deduplicator(ignore: Vec<Normalization>, now: Instant) -> Deduplicator {
+        Deduplicator::new(
+            &DedupArgs {
+                dedup: true,
+                dedup_ignore: ignore,
+                dedup_window: 10,
+            },
+            now,
+        )
+        .unwrap()
+    }
+
+    fn record(message: &str) -> LogRecord {
+        LogRecord::new(SystemTime::now(), message.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn count_repetitions() {
+        let start = Instant::now();
+        let mut dedup = deduplicator(
+            vec![Normalization::Timestamps, Normalization::Digits],
+            start,
+        );
+        assert!(!dedup.is_repeated(&record("2024-02-29T12:34:56Z connection 1 refused\n")));
+        assert!(dedup.is_repeated(&record("2024-02-29T12:34:57Z connection 2 refused\n")));
+        assert!(dedup.is_repeated(&record("2024-02-29T12:34:58Z connection 3 refused\n")));
+        assert!(dedup
+            .summary(start + Duration::from_secs(5), false)
+            .is_none());
+
+        let summary = dedup
+            .summary(start + Duration::from_secs(10), false)
+            .unwrap();
+        assert_eq!(summary.message, b"last message repeated 2 times\n");
+        assert!(dedup
+            .summary(start + Duration::from_secs(10), true)
+            .is_none());
+
+        assert!(!dedup.is_repeated(&record("connection established\n")));
+
+        let mut exact = deduplicator(vec![], start);
+        assert!(!exact.is_repeated(&record("connection 1 refused\n")));
+        assert!(!exact.is_repeated(&record("connection 2 refused\n")));
+    }
+
+    #[tokio::test]
+    async fn write_summary_before_next_log() {
+        let mut mock = MockAsyncLogWriter::new();
+        let mut seq = Sequence::new();
+        for message in [
+            &b"crash\n"[..],
+            b"last message repeated 2 times\n",
+            b"recovered\n",
+            b"last message repeated 1 times\n",
+        ] {
+            mock.expect_write_logs()
+                .withf(move |record| record.message == message)
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(|_| Ok(()));
+        }
+        mock.expect_close().times(1).returning(|| Ok(()));
+
+        let mut writer = DedupWriter::new(mock, deduplicator(vec![], Instant::now()));
+        for message in [
+            "crash\n",
+            "crash\n",
+            "crash\n",
+            "recovered\n",
+            "recovered\n",
+        ] {
+            writer.write_logs(&record(message)).await.unwrap();
+        }
+        // the last repetition is reported on close
+        writer.close().await.unwrap();
+    }
+}
@@ -0,0 +1,403 @@
+use clap::{Args, ValueEnum};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Repeated messages from the same place are logged at most once per interval.
+const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct DiagArgs {
+    #[arg(
+        long,
+        value_enum,
+        default_value = "warn",
+        help = "Level of the diagnostics of logup itself"
+    )]
+    log_level: Level,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write the diagnostics of logup itself to a file instead of stderr"
+    )]
+    log_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "log_file",
+        default_value = "10000000",
+        help = "Rotate the diagnostics file beyond the given size in bytes"
+    )]
+    log_file_max_size: u64,
+
+    #[arg(
+        long,
+        requires = "log_file",
+        default_value = "3",
+        help = "Number of rotated diagnostics files to keep"
+    )]
+    log_file_max_files: usize,
+}
+
+enum Output {
+    Stderr,
+    File {
+        path: PathBuf,
+        file: File,
+        size: u64,
+        max_size: u64,
+        max_files: usize,
+    },
+}
+
+impl Output {
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        match self {
+            Output::Stderr => std::io::stderr().write_all(line.as_bytes()),
+            Output::File {
+                path,
+                file,
+                size,
+                max_size,
+                max_files,
+            } => {
+                if *size > 0 && *size + line.len() as u64 > *max_size {
+                    rotate(path, *max_files)?;
+                    *file = open_append(path)?;
+                    *size = 0;
+                }
+                file.write_all(line.as_bytes())?;
+                *size += line.len() as u64;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Shifts `path.1` to `path.2` and so on, then moves `path` to `path.1`.
+fn rotate(path: &Path, max_files: usize) -> std::io::Result<()> {
+    if max_files == 0 {
+        return std::fs::remove_file(path);
+    }
+    for index in (1..max_files).rev() {
+        let from = rotated_path(path, index);
+        if from.exists() {
+            std::fs::rename(from, rotated_path(path, index + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated_path(path, 1))
+}
+
+/// When a message was last logged and how many times it was repeated since.
+struct Repeats {
+    last: Instant,
+    suppressed: u64,
+    level: Level,
+}
+
+/// Logger for the diagnostics of logup itself, kept apart from the logs being piped.
+pub struct DiagLogger {
+    level: Level,
+    output: Output,
+    // by call site, sink if any and message
+    limits: HashMap<(&'static str, Option<String>, String), Repeats>,
+}
+
+impl DiagLogger {
+    pub fn new(args: &DiagArgs) -> std::io::Result<Self> {
+        let output = match &args.log_file {
+            Some(path) => {
+                let file = open_append(path)?;
+                Output::File {
+                    path: path.clone(),
+                    size: file.metadata()?.len(),
+                    file,
+                    max_size: args.log_file_max_size,
+                    max_files: args.log_file_max_files,
+                }
+            }
+            None => Output::Stderr,
+        };
+        Ok(Self {
+            level: args.log_level,
+            output,
+            limits: HashMap::new(),
+        })
+    }
+
+    pub fn log(&mut self, level: Level, key: &'static str, sink: Option<&str>, message: &str) {
+        if level > self.level {
+            return;
+        }
+
+        let now = Instant::now();
+        let key = (key, sink.map(str::to_string), message.to_string());
+        if let Some(repeats) = self.limits.get_mut(&key) {
+            if now.duration_since(repeats.last) < RATE_LIMIT_INTERVAL {
+                repeats.suppressed += 1;
+                return;
+            }
+        }
+        let repeats = Repeats {
+            last: now,
+            suppressed: 0,
+            level,
+        };
+        let suppressed = self.limits.insert(key, repeats).map_or(0, |r| r.suppressed);
+        self.write(level, message, suppressed);
+    }
+
+    /// Reports the messages suppressed for a whole interval, or all of them,
+    /// e.g. at shutdown, and forgets the messages that were not repeated.
+    pub fn report_suppressed(&mut self, now: Instant, all: bool) {
+        let mut reports = vec![];
+        self.limits.retain(|(_, _, message), repeats| {
+            let elapsed = now.duration_since(repeats.last) >= RATE_LIMIT_INTERVAL;
+            if repeats.suppressed > 0 && (elapsed || all) {
+                reports.push((repeats.level, message.clone(), repeats.suppressed));
+                *repeats = Repeats {
+                    last: now,
+                    suppressed: 0,
+                    level: repeats.level,
+                };
+                return true;
+            }
+            !elapsed
+        });
+        for (level, message, suppressed) in reports {
+            self.write(level, &message, suppressed);
+        }
+    }
+
+    fn write(&mut self, level: Level, message: &str, suppressed: u64) {
+        let mut line = format!(
+            "{} [{}] {}",
+            format_timestamp(SystemTime::now()),
+            level.as_str(),
+            message
+        );
+        if suppressed > 0 {
+            line.push_str(&format!(" ({} similar messages suppressed)", suppressed));
+        }
+        line.push('\n');
+        // nowhere left to report the failure
+        let _ = self.output.write(&line);
+    }
+}
+
+static LOGGER: Mutex<Option<DiagLogger>> = Mutex::new(None);
+
+/// Sets up the global logger and routes panics through it.
+pub fn init(args: &DiagArgs) -> std::io::Result<()> {
+    let new_logger = DiagLogger::new(args)?;
+    let mut logger = LOGGER.lock().unwrap();
+    // e.g. on reload, before the counts are lost
+    if let Some(logger) = logger.as_mut() {
+        logger.report_suppressed(Instant::now(), true);
+    }
+    *logger = Some(new_logger);
+    drop(logger);
+    std::panic::set_hook(Box::new(|info| {
+        log(Level::Error, "panic", None, &info.to_string());
+    }));
+    Ok(())
+}
+
+/// Reports the suppressed messages every interval until the process exits.
+pub async fn report_suppressed_periodically() {
+    let mut interval = tokio::time::interval(RATE_LIMIT_INTERVAL);
+    loop {
+        interval.tick().await;
+        report_suppressed(false);
+    }
+}
+
+pub fn report_suppressed(all: bool) {
+    let mut logger = LOGGER.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(logger) = logger.as_mut() {
+        logger.report_suppressed(Instant::now(), all);
+    }
+}
+
+pub fn log(level: Level, key: &'static str, sink: Option<&str>, message: &str) {
+    let mut logger = LOGGER.lock().unwrap_or_else(|e| e.into_inner());
+    match logger.as_mut() {
+        Some(logger) => logger.log(level, key, sink, message),
+        None if level <= Level::Warn => eprintln!("[{}] {}", level.as_str(), message),
+        None => {}
+    }
+}
+
+/// Formats an error along with its chain of causes.
+pub fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    for cause in std::iter::successors(err.source(), |e| e.source()) {
+        message.push_str(&format!(": {}", cause));
+    }
+    message
+}
+
+/// Formats a time as RFC 3339 in UTC with millisecond precision.
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[macro_export]
+macro_rules! log_error {
+    (sink: $sink:expr, $($arg:tt)*) => {
+        $crate::diag::log($crate::diag::Level::Error, concat!(file!(), ":", line!()), Some(&$sink), &format!($($arg)*))
+    };
+    ($($arg:tt)*) => {
+        $crate::diag::log($crate::diag::Level::Error, concat!(file!(), ":", line!()), None, &format!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    (sink: $sink:expr, $($arg:tt)*) => {
+        $crate::diag::log($crate::diag::Level::Warn, concat!(file!(), ":", line!()), Some(&$sink), &format!($($arg)*))
+    };
+    ($($arg:tt)*) => {
+        $crate::diag::log($crate::diag::Level::Warn, concat!(file!(), ":", line!()), None, &format!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    (sink: $sink:expr, $($arg:tt)*) => {
+        $crate::diag::log($crate::diag::Level::Info, concat!(file!(), ":", line!()), Some(&$sink), &format!($($arg)*))
+    };
+    ($($arg:tt)*) => {
+        $crate::diag::log($crate::diag::Level::Info, concat!(file!(), ":", line!()), None, &format!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_args(path: &Path) -> DiagArgs {
+        DiagArgs {
+            log_level: Level::Info,
+            log_file: Some(path.to_path_buf()),
+            log_file_max_size: 100,
+            log_file_max_files: 2,
+        }
+    }
+
+    #[test]
+    fn format_rfc3339() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_millis(1709210096789)),
+            "2024-02-29T12:34:56.789Z"
+        );
+    }
+
+    #[test]
+    fn rate_limit_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("logup-diag-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("logup.log");
+
+        let mut logger = DiagLogger::new(&file_args(&path)).unwrap();
+        logger.log(Level::Debug, "a", None, "filtered");
+        logger.log(Level::Error, "a", None, "first");
+        logger.log(Level::Error, "a", None, "first");
+        logger.log(Level::Warn, "a", None, "second");
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.matches("first").count(), 1);
+        assert!(content.contains(" [ERROR] first\n"));
+        assert!(content.ends_with(" [WARN] second\n"));
+        assert!(!content.contains("filtered"));
+
+        // the count of the suppressed messages is reported once the interval is over
+        logger.report_suppressed(Instant::now(), false);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+        logger.report_suppressed(Instant::now() + RATE_LIMIT_INTERVAL, false);
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.ends_with(" [ERROR] first (1 similar messages suppressed)\n"));
+        assert_eq!(logger.limits.len(), 1);
+
+        // the limit is per call site, so distinct keys are written until rotation
+        for key in ["c", "d", "e", "f", "g", "h"] {
+            logger.log(Level::Info, key, None, key);
+        }
+        assert!(rotated_path(&path, 1).exists());
+        assert!(rotated_path(&path, 2).exists());
+        assert!(!rotated_path(&path, 3).exists());
+        assert!(std::fs::metadata(&path).unwrap().len() <= 100);
+
+        // and per sink for the messages about sinks
+        let path = dir.join("sinks.log");
+        let args = DiagArgs {
+            log_file_max_size: 10000,
+            ..file_args(&path)
+        };
+        let mut logger = DiagLogger::new(&args).unwrap();
+        logger.log(Level::Warn, "q", Some("aws"), "queue full");
+        logger.log(Level::Warn, "q", Some("newrelic"), "queue full");
+        logger.log(Level::Warn, "q", Some("aws"), "queue full");
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.matches("queue full").count(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,507 @@
+use crate::diag::error_chain;
+use crate::log_warn;
+use crate::record::LogRecord;
+use crate::writer::AsyncLogWriter;
+use async_trait::async_trait;
+use clap::{Args, ValueEnum};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const METADATA_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Cloud {
+    // EC2 instance identity from IMDSv2
+    Ec2,
+    // ECS task and container from the task metadata endpoint v4
+    Ecs,
+    // Kubernetes pod from the POD_NAME, POD_NAMESPACE, POD_UID, POD_IP and
+    // NODE_NAME variables and the labels file of the downward API
+    K8s,
+}
+
+#[derive(Args, PartialEq)]
+pub struct EnrichArgs {
+    #[arg(long, help = "Add the hostname to the logs as host.name")]
+    enrich_host: bool,
+
+    #[arg(
+        long,
+        help = "Add the pid and command of the process writing to stdin as process.pid and process.command"
+    )]
+    enrich_process: bool,
+
+    #[arg(
+        long,
+        value_name = "VAR",
+        help = "Add the environment variable to the logs as env.VAR"
+    )]
+    enrich_env: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "KEY=VALUE",
+        value_parser = parse_tag,
+        help = "Add the attribute to the logs"
+    )]
+    tag: Vec<(String, String)>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "PLATFORM",
+        help = "Add the metadata of the instance, task or pod running logup"
+    )]
+    enrich_cloud: Vec<Cloud>,
+
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "AWS_EC2_METADATA_SERVICE_ENDPOINT",
+        default_value = "http://169.254.169.254",
+        help = "Endpoint of the EC2 instance metadata service"
+    )]
+    enrich_imds_endpoint: String,
+
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "ECS_CONTAINER_METADATA_URI_V4",
+        help = "Endpoint of the ECS task metadata of the container"
+    )]
+    enrich_ecs_endpoint: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = "/etc/podinfo/labels",
+        help = "Labels file of the Kubernetes downward API"
+    )]
+    enrich_k8s_labels: PathBuf,
+}
+
+fn parse_tag(tag: &str) -> Result<(String, String), String> {
+    match tag.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err("expected KEY=VALUE".to_string()),
+    }
+}
+
+pub struct Enricher {
+    attributes: BTreeMap<String, Value>,
+    // the writer of the pipe is looked up once it has written something, by
+    // then it has exec'd the actual program
+    process_pending: bool,
+}
+
+impl Enricher {
+    pub async fn new(args: &EnrichArgs) -> Option<Self> {
+        let mut attributes = BTreeMap::new();
+        if args.enrich_host {
+            attributes.insert(
+                "host.name".to_string(),
+                hostname::get().unwrap().to_string_lossy().into(),
+            );
+        }
+        for var in &args.enrich_env {
+            if let Ok(value) = std::env::var(var) {
+                attributes.insert(format!("env.{}", var), value.into());
+            }
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(METADATA_TIMEOUT)
+            .build()
+            .unwrap();
+        for cloud in &args.enrich_cloud {
+            let fetched = match cloud {
+                Cloud::Ec2 => ec2_metadata(&client, &args.enrich_imds_endpoint)
+                    .await
+                    .map_err(|e| format!("Error fetching EC2 metadata: {}", error_chain(&e))),
+                Cloud::Ecs => match &args.enrich_ecs_endpoint {
+                    Some(endpoint) => ecs_metadata(&client, endpoint)
+                        .await
+                        .map_err(|e| format!("Error fetching ECS metadata: {}", error_chain(&e))),
+                    None => Err("ECS_CONTAINER_METADATA_URI_V4 is not set".to_string()),
+                },
+                Cloud::K8s => Ok(k8s_metadata(
+                    |var| std::env::var(var).ok(),
+                    &args.enrich_k8s_labels,
+                )),
+            };
+            match fetched {
+                Ok(fetched) => attributes.extend(fetched),
+                Err(e) => log_warn!("{}", e),
+            }
+        }
+
+        // tags win over the detected metadata
+        for (key, value) in &args.tag {
+            attributes.insert(key.clone(), value.clone().into());
+        }
+
+        if attributes.is_empty() && !args.enrich_process {
+            return None;
+        }
+        Some(Self {
+            attributes,
+            process_pending: args.enrich_process,
+        })
+    }
+
+    /// Adds the attributes, keeping the ones the record already has.
+    pub fn enrich(&mut self, record: &mut LogRecord) {
+        if self.process_pending {
+            self.process_pending = false;
+            match stdin_writer_pid() {
+                Some(pid) => self.attributes.extend(process_metadata(pid)),
+                None => log_warn!("Could not find the process writing to stdin"),
+            }
+        }
+        for (key, value) in &self.attributes {
+            if !record.attributes.contains_key(key) {
+                record.attributes.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+async fn ec2_metadata(
+    client: &reqwest::Client,
+    endpoint: &str,
+) -> Result<BTreeMap<String, Value>, reqwest::Error> {
+    let endpoint = endpoint.trim_end_matches('/');
+    let token = client
+        .put(format!("{}/latest/api/token", endpoint))
+        .header("X-aws-ec2-metadata-token-ttl-seconds", "60")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let document: Value = client
+        .get(format!(
+            "{}/latest/dynamic/instance-identity/document",
+            endpoint
+        ))
+        .header("X-aws-ec2-metadata-token", token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut attributes = BTreeMap::new();
+    attributes.insert("cloud.provider".to_string(), "aws".into());
+    attributes.insert("cloud.platform".to_string(), "aws_ec2".into());
+    for (field, key) in [
+        ("region", "cloud.region"),
+        ("availabilityZone", "cloud.availability_zone"),
+        ("accountId", "cloud.account.id"),
+        ("instanceId", "host.id"),
+        ("instanceType", "host.type"),
+        ("imageId", "host.image.id"),
+    ] {
+        if let Some(value) = document.get(field) {
+            attributes.insert(key.to_string(), value.clone());
+        }
+    }
+    Ok(attributes)
+}
+
+async fn ecs_metadata(
+    client: &reqwest::Client,
+    endpoint: &str,
+) -> Result<BTreeMap<String, Value>, reqwest::Error> {
+    let endpoint = endpoint.trim_end_matches('/');
+    let container: Value = client
+        .get(endpoint)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let task: Value = client
+        .get(format!("{}/task", endpoint))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut attributes = BTreeMap::new();
+    attributes.insert("cloud.provider".to_string(), "aws".into());
+    attributes.insert("cloud.platform".to_string(), "aws_ecs".into());
+    for (json, field, key) in [
+        (&task, "Cluster", "aws.ecs.cluster.arn"),
+        (&task, "TaskARN", "aws.ecs.task.arn"),
+        (&task, "Family", "aws.ecs.task.family"),
+        (&task, "Revision", "aws.ecs.task.revision"),
+        (&task, "LaunchType", "aws.ecs.launchtype"),
+        (&task, "AvailabilityZone", "cloud.availability_zone"),
+        (&container, "Name", "container.name"),
+        (&container, "DockerId", "container.id"),
+        (&container, "Image", "container.image.name"),
+    ] {
+        if let Some(value) = json.get(field) {
+            attributes.insert(key.to_string(), value.clone());
+        }
+    }
+    // arn:aws:ecs:REGION:ACCOUNT:task/...
+    let arn = task.get("TaskARN").and_then(Value::as_str).unwrap_or("");
+    if let Some(region) = arn.split(':').nth(3).filter(|r| !r.is_empty()) {
+        attributes.insert("cloud.region".to_string(), region.into());
+    }
+    Ok(attributes)
+}
+
+fn k8s_metadata(env: impl Fn(&str) -> Option<String>, labels: &Path) -> BTreeMap<String, Value> {
+    let mut attributes = BTreeMap::new();
+    for (var, key) in [
+        ("POD_NAME", "k8s.pod.name"),
+        ("POD_NAMESPACE", "k8s.namespace.name"),
+        ("POD_UID", "k8s.pod.uid"),
+        ("POD_IP", "k8s.pod.ip"),
+        ("NODE_NAME", "k8s.node.name"),
+    ] {
+        if let Some(value) = env(var) {
+            attributes.insert(key.to_string(), value.into());
+        }
+    }
+    // one key="value" per line
+    let labels = std::fs::read_to_string(labels).unwrap_or_default();
+    for line in labels.lines() {
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim_matches('"');
+            attributes.insert(format!("k8s.pod.label.{}", key), value.into());
+        }
+    }
+    attributes
+}
+
+/// Another process holding the write end of the pipe on stdin, if any.
+fn stdin_writer_pid() -> Option<u32> {
+    let pipe = std::fs::read_link("/proc/self/fd/0").ok()?;
+    pipe_writer_pid(&pipe, std::process::id())
+}
+
+fn pipe_writer_pid(pipe: &Path, own_pid: u32) -> Option<u32> {
+    if !pipe.to_string_lossy().starts_with("pipe:") {
+        return None;
+    }
+    for process in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Some(pid) = process.file_name().to_str().and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+        if pid == own_pid {
+            continue;
+        }
+        let Ok(fds) = std::fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            if std::fs::read_link(fd.path()).is_ok_and(|target| target == pipe)
+                && is_write_only(&process.path().join("fdinfo").join(fd.file_name()))
+            {
+                return Some(pid);
+            }
+        }
+    }
+    None
+}
+
+fn is_write_only(fdinfo: &Path) -> bool {
+    let fdinfo = std::fs::read_to_string(fdinfo).unwrap_or_default();
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .and_then(|flags| i32::from_str_radix(flags.trim(), 8).ok())
+        .is_some_and(|flags| flags & libc::O_ACCMODE == libc::O_WRONLY)
+}
+
+fn process_metadata(pid: u32) -> BTreeMap<String, Value> {
+    let mut attributes = BTreeMap::new();
+    attributes.insert("process.pid".to_string(), pid.into());
+    if let Ok(comm) = std::fs::read_to_string(format!("/proc/{}/comm", pid)) {
+        attributes.insert("process.command".to_string(), comm.trim_end().into());
+    }
+    attributes
+}
+
+/// Adds the host, process, environment and cloud attributes to each record.
+pub struct EnrichWriter<T: AsyncLogWriter> {
+    inner: T,
+    // shared with the next pipeline on reload
+    enricher: Arc<Mutex<Enricher>>,
+}
+
+impl<T: AsyncLogWriter> EnrichWriter<T> {
+    pub fn new(inner: T, enricher: Arc<Mutex<Enricher>>) -> Self {
+        Self { inner, enricher }
+    }
+}
+
+#[async_trait]
+impl<T: AsyncLogWriter + Send> AsyncLogWriter for EnrichWriter<T> {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let mut record = record.clone();
+        self.enricher.lock().unwrap().enrich(&mut record);
+        self.inner.write_logs(&record).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::SystemTime;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn args(clouds: Vec<Cloud>, endpoint: &str) -> EnrichArgs {
+        EnrichArgs {
+            enrich_host: false,
+            enrich_process: false,
+            enrich_env: vec![],
+            tag: vec![],
+            enrich_cloud: clouds,
+            enrich_imds_endpoint: endpoint.to_string(),
+            enrich_ecs_endpoint: Some(format!("{}/v4/abc", endpoint)),
+            enrich_k8s_labels: PathBuf::from("/nonexistent"),
+        }
+    }
+
+    /// Serves the given body for each path, requiring the IMDSv2 token on the
+    /// identity document.
+    async fn serve_metadata(routes: Vec<(&'static str, &'static str)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0u8; 4096];
+                let size = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..size]).to_string();
+                let path = request.split(' ').nth(1).unwrap_or("");
+                let authorized = !path.contains("identity")
+                    || request.contains("x-aws-ec2-metadata-token: secret");
+                let (status, body) = match routes.iter().find(|(p, _)| *p == path) {
+                    Some((_, body)) if authorized => ("200 OK", *body),
+                    Some(_) => ("401 Unauthorized", ""),
+                    None => ("404 Not Found", ""),
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn fetch_cloud_metadata() {
+        let endpoint = serve_metadata(vec![
+            ("/latest/api/token", "secret"),
+            (
+                "/latest/dynamic/instance-identity/document",
+                r#"{"region":"eu-west-1","availabilityZone":"eu-west-1a","instanceId":"i-123","instanceType":"t3.micro"}"#,
+            ),
+            ("/v4/abc", r#"{"Name":"app","DockerId":"c0ffee"}"#),
+            (
+                "/v4/abc/task",
+                r#"{"Cluster":"prod","TaskARN":"arn:aws:ecs:us-east-1:123:task/prod/1","Family":"app","Revision":"3"}"#,
+            ),
+        ])
+        .await;
+
+        let mut enricher = Enricher::new(&args(vec![Cloud::Ec2], &endpoint))
+            .await
+            .unwrap();
+        let mut record = LogRecord::new(SystemTime::now(), b"hello\n".to_vec());
+        record
+            .attributes
+            .insert("host.id".to_string(), json!("mine"));
+        enricher.enrich(&mut record);
+        assert_eq!(record.attributes["cloud.region"], json!("eu-west-1"));
+        assert_eq!(record.attributes["host.type"], json!("t3.micro"));
+        assert_eq!(record.attributes["host.id"], json!("mine"));
+
+        let ecs = Enricher::new(&args(vec![Cloud::Ecs], &endpoint))
+            .await
+            .unwrap();
+        assert_eq!(ecs.attributes["container.name"], json!("app"));
+        assert_eq!(ecs.attributes["aws.ecs.task.family"], json!("app"));
+        assert_eq!(ecs.attributes["cloud.region"], json!("us-east-1"));
+
+        // unreachable metadata is not fatal
+        assert!(Enricher::new(&args(vec![Cloud::Ec2], "http://127.0.0.1:1"))
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn add_tags_env_and_k8s() {
+        let mut args = args(vec![], "http://127.0.0.1:1");
+        args.enrich_env = vec!["PATH".to_string(), "LOGUP_UNSET_VAR".to_string()];
+        args.tag = vec![parse_tag("team=core=1").unwrap()];
+        let enricher = Enricher::new(&args).await.unwrap();
+        assert!(enricher.attributes.contains_key("env.PATH"));
+        assert!(!enricher.attributes.contains_key("env.LOGUP_UNSET_VAR"));
+        assert_eq!(enricher.attributes["team"], json!("core=1"));
+        assert!(parse_tag("=x").is_err());
+        assert!(parse_tag("x").is_err());
+
+        let labels = std::env::temp_dir().join(format!("logup-labels-{}", std::process::id()));
+        std::fs::write(&labels, "app=\"web\"\ntier=\"front\"\n").unwrap();
+        let k8s = k8s_metadata(
+            |var| (var == "POD_NAME").then(|| "web-1".to_string()),
+            &labels,
+        );
+        std::fs::remove_file(&labels).unwrap();
+        assert_eq!(
+            k8s,
+            BTreeMap::from([
+                ("k8s.pod.name".to_string(), json!("web-1")),
+                ("k8s.pod.label.app".to_string(), json!("web")),
+                ("k8s.pod.label.tier".to_string(), json!("front")),
+            ])
+        );
+    }
+
+    #[test]
+    fn find_pipe_writer() {
+        let (reader, writer) = std::io::pipe().unwrap();
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .stdout(writer)
+            .spawn()
+            .unwrap();
+        let pipe = std::fs::read_link(format!(
+            "/proc/self/fd/{}",
+            std::os::fd::AsRawFd::as_raw_fd(&reader)
+        ))
+        .unwrap();
+        let pid = pipe_writer_pid(&pipe, std::process::id());
+        let process = process_metadata(child.id());
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert_eq!(pid, Some(child.id()));
+        assert_eq!(process["process.command"], json!("sleep"));
+        assert_eq!(pipe_writer_pid(Path::new("/dev/null"), 0), None);
+    }
+}
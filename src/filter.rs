@@ -0,0 +1,234 @@
+use crate::record::{LogRecord, Severity};
+use crate::writer::AsyncLogWriter;
+use async_trait::async_trait;
+use clap::Args;
+use regex::Regex;
+use serde_json::Value;
+
+#[derive(Clone, Debug)]
+enum Condition {
+    Include(Regex),
+    Exclude(Regex),
+    IncludeField(String, Regex),
+    ExcludeField(String, Regex),
+    MinLevel(Severity),
+}
+
+/// A condition, applying to all the sinks unless a sink is given.
+#[derive(Clone, Debug)]
+pub struct FilterRule {
+    sink: Option<String>,
+    condition: Condition,
+}
+
+impl FilterRule {
+    /// Parses `[SINK:]KIND=VALUE`, e.g. `newrelic:min-level=warn`.
+    fn parse(rule: &str) -> Result<Self, String> {
+        let (target, value) = rule.split_once('=').ok_or("expected [SINK:]KIND=VALUE")?;
+        let (sink, kind) = match target.split_once(':') {
+            Some((sink, kind)) => (Some(sink.to_string()), kind),
+            None => (None, target),
+        };
+        let regex = |regex: &str| Regex::new(regex).map_err(|e| e.to_string());
+        let field = |value: &str| -> Result<(String, Regex), String> {
+            let (key, value) = value.split_once('=').ok_or("expected KEY=REGEX")?;
+            Ok((key.to_string(), regex(value)?))
+        };
+        let condition = match kind {
+            "include" => Condition::Include(regex(value)?),
+            "exclude" => Condition::Exclude(regex(value)?),
+            "include-field" => {
+                let (key, regex) = field(value)?;
+                Condition::IncludeField(key, regex)
+            }
+            "exclude-field" => {
+                let (key, regex) = field(value)?;
+                Condition::ExcludeField(key, regex)
+            }
+            "min-level" => Condition::MinLevel(
+                Severity::parse(value).ok_or_else(|| format!("unknown level {}", value))?,
+            ),
+            _ => {
+                return Err(format!(
+                    "unknown filter {}, expected include, exclude, include-field, exclude-field or min-level",
+                    kind
+                ))
+            }
+        };
+        Ok(Self { sink, condition })
+    }
+
+    pub fn sink(&self) -> Option<&str> {
+        self.sink.as_deref()
+    }
+}
+
+#[derive(Args)]
+pub struct FilterArgs {
+    #[arg(
+        long,
+        value_name = "[SINK:]KIND=VALUE",
+        value_parser = FilterRule::parse,
+        help = "Send only some logs to the sinks, stdout is not filtered. KIND is include or exclude with a regex, include-field or exclude-field with KEY=REGEX, or min-level with a level"
+    )]
+    pub filter: Vec<FilterRule>,
+}
+
+/// Keeps a record if it matches any of the include conditions, if any, and
+/// none of the exclude ones. Records without a severity pass min-level.
+pub struct Filter {
+    conditions: Vec<Condition>,
+}
+
+impl Filter {
+    /// The filter of the given sink, or of all the sinks if None.
+    pub fn new(rules: &[FilterRule], sink: Option<&str>) -> Option<Self> {
+        let conditions: Vec<Condition> = rules
+            .iter()
+            .filter(|rule| rule.sink.as_deref() == sink)
+            .map(|rule| rule.condition.clone())
+            .collect();
+        if conditions.is_empty() {
+            return None;
+        }
+        Some(Self { conditions })
+    }
+
+    pub fn matches(&self, record: &LogRecord) -> bool {
+        let message = String::from_utf8_lossy(&record.message);
+        let field_matches = |key: &str, regex: &Regex| match record.attributes.get(key) {
+            Some(Value::String(value)) => regex.is_match(value),
+            Some(value) => regex.is_match(&value.to_string()),
+            None => false,
+        };
+
+        let mut has_include = false;
+        let mut included = false;
+        for condition in &self.conditions {
+            match condition {
+                Condition::Include(regex) => {
+                    has_include = true;
+                    included |= regex.is_match(&message);
+                }
+                Condition::IncludeField(key, regex) => {
+                    has_include = true;
+                    included |= field_matches(key, regex);
+                }
+                Condition::Exclude(regex) if regex.is_match(&message) => return false,
+                Condition::ExcludeField(key, regex) if field_matches(key, regex) => return false,
+                Condition::MinLevel(min) if record.severity.is_some_and(|s| s < *min) => {
+                    return false
+                }
+                _ => {}
+            }
+        }
+        !has_include || included
+    }
+}
+
+/// Drops the records not matching the filter.
+pub struct FilterWriter<T: AsyncLogWriter> {
+    inner: T,
+    filter: Filter,
+}
+
+impl<T: AsyncLogWriter> FilterWriter<T> {
+    pub fn new(inner: T, filter: Filter) -> Self {
+        Self { inner, filter }
+    }
+}
+
+#[async_trait]
+impl<T: AsyncLogWriter + Send> AsyncLogWriter for FilterWriter<T> {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        if !self.filter.matches(record) {
+            return Ok(());
+        }
+        self.inner.write_logs(record).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::MockAsyncLogWriter;
+    use mockall::predicate::eq;
+    use serde_json::json;
+    use std::time::SystemTime;
+
+    fn filter(rules: &[&str], sink: Option<&str>) -> Option<Filter> {
+        let rules: Vec<FilterRule> = rules
+            .iter()
+            .map(|rule| FilterRule::parse(rule).unwrap())
+            .collect();
+        Filter::new(&rules, sink)
+    }
+
+    fn record(message: &str, severity: Option<Severity>) -> LogRecord {
+        let mut record = LogRecord::new(SystemTime::now(), message.as_bytes().to_vec());
+        record.severity = severity;
+        record
+    }
+
+    #[test]
+    fn match_conditions() {
+        let rules = [
+            "exclude=GET /health",
+            "min-level=info",
+            "newrelic:include=ERROR",
+            "newrelic:include-field=user=^bob$",
+        ];
+        let all = filter(&rules, None).unwrap();
+        assert!(all.matches(&record("GET /users\n", None)));
+        assert!(!all.matches(&record("GET /health 200\n", None)));
+        assert!(!all.matches(&record("verbose\n", Some(Severity::Debug))));
+        assert!(all.matches(&record("verbose\n", Some(Severity::Warn))));
+
+        let newrelic = filter(&rules, Some("newrelic")).unwrap();
+        assert!(newrelic.matches(&record("ERROR disk full\n", None)));
+        assert!(!newrelic.matches(&record("INFO started\n", None)));
+        let mut bob = record("INFO started\n", None);
+        bob.attributes.insert("user".to_string(), json!("bob"));
+        assert!(newrelic.matches(&bob));
+
+        assert!(filter(&rules, Some("aws")).is_none());
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rule = FilterRule::parse("aws:exclude-field=path=^/a:b=c").unwrap();
+        assert_eq!(rule.sink(), Some("aws"));
+        assert!(FilterRule::parse("exclude").is_err());
+        assert!(FilterRule::parse("drop=x").is_err());
+        assert!(FilterRule::parse("min-level=loud").is_err());
+        assert!(FilterRule::parse("include-field=user").is_err());
+        assert!(FilterRule::parse("include=(").is_err());
+    }
+
+    #[tokio::test]
+    async fn drop_filtered_records() {
+        let mut mock = MockAsyncLogWriter::new();
+        let kept = record("GET /users\n", None);
+
+        mock.expect_write_logs()
+            .with(eq(kept.clone()))
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_write_logs().times(0);
+
+        let mut writer = FilterWriter::new(mock, filter(&["exclude=health"], None).unwrap());
+        writer.write_logs(&kept).await.unwrap();
+        writer
+            .write_logs(&record("GET /health\n", None))
+            .await
+            .unwrap();
+    }
+}
@@ -1,35 +1,162 @@
+mod batch;
+mod config;
+mod dedup;
+pub mod diag;
+mod enrich;
+mod filter;
+mod limit;
+mod metrics;
+mod parser;
+mod parser_logfmt;
+mod parser_regex;
 mod reader;
+pub mod record;
+mod redact;
+mod severity;
+mod spill;
+mod timestamp;
+mod validate;
 mod writer;
+mod writer_ansi;
 mod writer_aws;
+mod writer_dry_run;
+mod writer_firehose;
+mod writer_journald;
+mod writer_kinesis;
 mod writer_lines;
 mod writer_multi;
+mod writer_multiline;
 mod writer_newrelic;
 mod writer_queue;
+mod writer_router;
 
+use crate::batch::MAX_BATCH_DELAY;
+use crate::dedup::{DedupArgs, DedupWriter, Deduplicator};
+use crate::diag::{error_chain, DiagArgs};
+use crate::enrich::{EnrichArgs, EnrichWriter, Enricher};
+use crate::filter::{Filter, FilterArgs, FilterWriter};
+use crate::limit::{LimitArgs, LimitWriter, Limiter};
+use crate::metrics::metrics;
+use crate::parser::{ParseArgs, Parser as LineParser, ParserWriter};
 use crate::reader::AsyncLogReader;
+use crate::record::LogRecord;
+use crate::redact::{RedactArgs, RedactWriter, Redactor};
+use crate::severity::{SeverityArgs, SeverityDetector, SeverityWriter};
+use crate::timestamp::{TimestampArgs, TimestampExtractor, TimestampWriter};
 use crate::writer::AsyncLogWriter;
+use crate::writer_ansi::AnsiStripWriter;
 use crate::writer_aws::{AWSArgs, AWSLogsWriter};
+use crate::writer_dry_run::DryRunWriter;
+use crate::writer_firehose::{FirehoseArgs, FirehoseWriter};
+use crate::writer_journald::{JournaldArgs, JournaldWriter};
+use crate::writer_kinesis::{KinesisArgs, KinesisWriter};
 use crate::writer_lines::LinesWriter;
 use crate::writer_multi::MultiWriter;
+use crate::writer_multiline::{MultilineArgs, MultilineRules, MultilineWriter};
 use crate::writer_newrelic::{NewRelicArgs, NewRelicWriter};
-use crate::writer_queue::QueueWriter;
-use clap::Parser;
+use crate::writer_queue::{MemoryBudget, Overflow, Policy, QueueArgs, QueueWriter};
+use crate::writer_router::{RouteArgs, RouterWriter};
+use async_trait::async_trait;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::ffi::OsString;
+use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::fs::File;
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio::task::JoinHandle;
 
+/// How long a partial line may wait in the stdout passthrough when it is line buffered.
+const STDOUT_IDLE_FLUSH: Duration = Duration::from_millis(100);
+
+/// How often the summaries of limits and repetitions are checked while the input is idle.
+const SUMMARY_IDLE_FLUSH: Duration = Duration::from_secs(1);
+
+/// The sinks, also parsed alone for each sink of the configuration file.
+#[derive(Parser, Clone)]
+pub struct SinkArgs {
+    #[command(flatten)]
+    aws: AWSArgs,
+
+    #[command(flatten)]
+    kinesis: KinesisArgs,
+
+    #[command(flatten)]
+    firehose: FirehoseArgs,
+
+    #[command(flatten)]
+    newrelic: NewRelicArgs,
+
+    #[command(flatten)]
+    journald: JournaldArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check the configuration, the credentials and the sinks, and print the
+    /// plan without sending any log
+    Validate,
+}
+
 #[derive(Parser)]
 #[command(
     version,
-    about = "Find examples on https://github.com/lucabrunox/logup"
+    about = "Find examples on https://github.com/lucabrunox/logup",
+    args_override_self = true
 )]
 pub struct LogupArgs {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Read the options and any number of named sinks from a TOML file, the options given on the command line replace those of the file"
+    )]
+    config: Option<PathBuf>,
+
     #[command(flatten)]
-    aws: AWSArgs,
+    sinks: SinkArgs,
+
+    // named sinks of the configuration file
+    #[arg(skip)]
+    config_sinks: Vec<(String, SinkArgs)>,
 
     #[command(flatten)]
-    newrelic: NewRelicArgs,
+    multiline: MultilineArgs,
+
+    #[command(flatten)]
+    parse: ParseArgs,
+
+    #[command(flatten)]
+    timestamp: TimestampArgs,
+
+    #[command(flatten)]
+    severity: SeverityArgs,
+
+    #[command(flatten)]
+    enrich: EnrichArgs,
+
+    #[command(flatten)]
+    route: RouteArgs,
+
+    #[command(flatten)]
+    filter: FilterArgs,
+
+    #[command(flatten)]
+    redact: RedactArgs,
+
+    #[command(flatten)]
+    limit: LimitArgs,
+
+    #[command(flatten)]
+    dedup: DedupArgs,
+
+    #[command(flatten)]
+    diag: DiagArgs,
 
     #[arg(
         long,
@@ -38,94 +165,698 @@ pub struct LogupArgs {
     )]
     max_line_size: usize,
 
-    #[arg(
-        long,
-        requires = "aws",
-        help = "Max logs to keep in memory before dropping the incoming ones",
-        default_value = "1000"
-    )]
-    max_memory_items: usize,
+    #[command(flatten)]
+    queue: QueueArgs,
 
     #[arg(
         long,
-        requires = "aws",
         help = "Max retries before dropping a log",
         default_value = "100"
     )]
     max_retries: u32,
 
+    #[arg(
+        long,
+        help = "Strip colors and other terminal escape sequences from the logs sent to the sinks, stdout keeps them"
+    )]
+    strip_ansi: bool,
+
+    #[arg(
+        long,
+        help = "Run the pipeline but print to stderr what each sink would send instead of sending it"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long,
+        help = "Max seconds to wait for pending logs to be delivered before exiting",
+        default_value = "10"
+    )]
+    shutdown_timeout: u64,
+
+    #[arg(
+        long,
+        value_name = "ADDR",
+        help = "Expose Prometheus metrics of logup itself on http://ADDR/metrics"
+    )]
+    metrics_listen: Option<SocketAddr>,
+
     #[arg(help = "Read logs from a file instead of stdin")]
     input_file: Option<PathBuf>,
 }
 
-pub async fn run(args: LogupArgs) {
-    let mut handles: Vec<JoinHandle<()>> = vec![];
+impl LogupArgs {
+    /// Parses the command line on top of the configuration file, if any.
+    pub fn parse_with_config() -> Self {
+        Self::try_parse_with_config().unwrap_or_else(|e| e.exit())
+    }
+
+    /// Like parse_with_config but returning the errors, e.g. when reloading.
+    pub fn try_parse_with_config() -> Result<Self, clap::Error> {
+        let cli: Vec<OsString> = std::env::args_os().collect();
+        let matches = Self::command().try_get_matches_from(&cli)?;
+        let args = Self::from_arg_matches(&matches)?;
+        let Some(path) = &args.config else {
+            return args.check();
+        };
+        let config =
+            config::load(path).map_err(|e| Self::command().error(ErrorKind::InvalidValue, e))?;
+
+        // the options of the command line replace those of the file, lists
+        // included, rather than adding to them
+        let given = |key: &str| {
+            let id = key.replace('-', "_");
+            matches.ids().any(|given| {
+                given.as_str() == id && matches.value_source(&id) == Some(ValueSource::CommandLine)
+            })
+        };
+        let mut merged = vec![cli[0].clone()];
+        for (key, config_args) in config.args {
+            if !given(&key) {
+                merged.extend(config_args);
+            }
+        }
+        merged.extend(cli[1..].iter().cloned());
+        let mut args = Self::try_parse_from(merged)?;
+        args.input_file = args.input_file.or(config.input_file);
+        for (name, sink) in config.sinks {
+            let sink_args = parse_config_sink(std::iter::once(cli[0].clone()).chain(sink))
+                .map_err(|e| {
+                    let message = format!(
+                        "invalid sink {} in {}: {}",
+                        name,
+                        path.display(),
+                        error_message(&e)
+                    );
+                    Self::command().error(ErrorKind::InvalidValue, message)
+                })?;
+            args.config_sinks.push((name, sink_args));
+        }
+        args.check()
+    }
+
+    /// Rejects the options that can't be combined.
+    fn check(self) -> Result<Self, clap::Error> {
+        self.limit
+            .check()
+            .map_err(|e| Self::command().error(ErrorKind::ArgumentConflict, e))?;
+        Ok(self)
+    }
+
+    /// The sinks of the command line, then the named ones of the configuration file.
+    fn all_sinks(&self) -> impl Iterator<Item = (Option<&str>, &SinkArgs)> {
+        let config_sinks = self.config_sinks.iter();
+        std::iter::once((None, &self.sinks))
+            .chain(config_sinks.map(|(name, sink)| (Some(name.as_str()), sink)))
+    }
+}
+
+/// Parses a named sink of the configuration file, taking its options from
+/// its table only rather than from the environment variables, which are meant
+/// for the sinks of the command line.
+fn parse_config_sink(args: impl IntoIterator<Item = OsString>) -> Result<SinkArgs, clap::Error> {
+    let command = SinkArgs::command().mut_args(|arg| arg.env(None));
+    SinkArgs::from_arg_matches(&command.try_get_matches_from(args)?)
+}
+
+/// The message of a clap error, without the usage.
+fn error_message(error: &clap::Error) -> String {
+    let error = error.to_string();
+    let error = error.split("\n\n").next().unwrap_or_default();
+    error.trim_start_matches("error: ").to_string()
+}
+
+impl SinkArgs {
+    /// Whether the options of the given type of sink are the same.
+    fn same(&self, kind: &str, other: &SinkArgs) -> bool {
+        match kind {
+            "aws" => self.aws == other.aws,
+            "kinesis" => self.kinesis == other.kinesis,
+            "firehose" => self.firehose == other.firehose,
+            "newrelic" => self.newrelic == other.newrelic,
+            "journald" => self.journald == other.journald,
+            _ => false,
+        }
+    }
+}
+
+/// Type, name and destination of the enabled sinks, named after their type
+/// unless a name is given.
+fn describe_sinks(args: &SinkArgs, name: Option<&str>) -> Vec<(&'static str, String, String)> {
+    [
+        ("aws", writer_aws::describe(&args.aws)),
+        ("kinesis", writer_kinesis::describe(&args.kinesis)),
+        ("firehose", writer_firehose::describe(&args.firehose)),
+        ("newrelic", writer_newrelic::describe(&args.newrelic)),
+        ("journald", writer_journald::describe(&args.journald)),
+    ]
+    .into_iter()
+    .filter_map(|(kind, description)| Some((kind, name.unwrap_or(kind).to_string(), description?)))
+    .collect()
+}
+
+async fn open_sink(
+    kind: &str,
+    args: &SinkArgs,
+    name: &str,
+    max_retries: u32,
+    dry_run: bool,
+) -> Option<Box<dyn AsyncLogWriter + Send>> {
+    if dry_run {
+        return Some(Box::new(DryRunWriter::new(name)));
+    }
+    match kind {
+        "aws" => Some(Box::new(
+            AWSLogsWriter::new(&args.aws, max_retries, name).await?,
+        )),
+        "kinesis" => Some(Box::new(
+            KinesisWriter::new(&args.kinesis, max_retries, name).await?,
+        )),
+        "firehose" => Some(Box::new(
+            FirehoseWriter::new(&args.firehose, max_retries, name).await?,
+        )),
+        "newrelic" => Some(Box::new(NewRelicWriter::new(&args.newrelic, name)?)),
+        "journald" => match JournaldWriter::new(&args.journald, name) {
+            Ok(writer) => Some(Box::new(writer?)),
+            Err(e) => {
+                log_error!("Error connecting to journald: {}", error_chain(&e));
+                None
+            }
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// A sink behind its queue, kept across reloads while its options don't change.
+struct OpenSink {
+    kind: &'static str,
+    name: String,
+    args: SinkArgs,
+    max_retries: u32,
+    dry_run: bool,
+    max_items: usize,
+    max_bytes: Option<usize>,
+    overflow: Overflow,
+    spill_dir: PathBuf,
+    queue: QueueWriter,
+}
+
+impl OpenSink {
+    fn same(&self, kind: &str, name: &str, sink_args: &SinkArgs, args: &LogupArgs) -> bool {
+        self.name == name
+            && self.kind == kind
+            && self.args.same(kind, sink_args)
+            && self.max_retries == args.max_retries
+            && self.dry_run == args.dry_run
+            && self.max_items == args.queue.max_memory_items
+            && self.max_bytes == args.queue.max_memory_bytes(Some(name))
+            && self.overflow == args.queue.overflow(name)
+            && self.spill_dir == args.queue.spill_dir
+    }
+}
+
+/// Opens the enabled sinks, reusing the previous ones with the same options so
+/// that their queued logs are kept, and closing the others.
+async fn open_sinks(
+    args: &LogupArgs,
+    previous: Vec<OpenSink>,
+    budget: &Arc<MemoryBudget>,
+    handles: &mut Vec<JoinHandle<()>>,
+) -> Vec<OpenSink> {
+    let mut enabled: Vec<(&'static str, String, &SinkArgs)> = vec![];
+    for (name, sink_args) in args.all_sinks() {
+        for (kind, name, _) in describe_sinks(sink_args, name) {
+            if enabled.iter().any(|(_, other, _)| *other == name) {
+                log_error!(
+                    sink: name,
+                    "Sink {} is defined twice, ignoring the configuration file",
+                    name
+                );
+                continue;
+            }
+            enabled.push((kind, name, sink_args));
+        }
+    }
+
+    let (mut kept, removed): (Vec<OpenSink>, Vec<OpenSink>) =
+        previous.into_iter().partition(|sink| {
+            enabled
+                .iter()
+                .any(|(kind, name, sink_args)| sink.same(kind, name, sink_args, args))
+        });
+    for mut sink in removed {
+        let respilled = enabled.iter().any(|(_, name, _)| *name == sink.name)
+            && args.queue.overflow(&sink.name) == Overflow::SpillToDisk
+            && args.queue.spill_dir == sink.spill_dir;
+        if respilled {
+            // the new sink sends what was queued, from the same spill file
+            sink.queue.release_spill().await;
+        }
+        // deliver what was queued for the removed sinks in the background
+        tokio::spawn(async move {
+            let _ = sink.queue.close().await;
+        });
+    }
 
-    {
-        let mut reader = match args.input_file {
-            Some(path) => Box::new(File::open(path).await.unwrap()),
-            None => Box::new(tokio::io::stdin()) as Box<dyn AsyncLogReader + Send>,
+    let mut sinks: Vec<OpenSink> = vec![];
+    for (kind, name, sink_args) in enabled {
+        if let Some(index) = kept.iter().position(|sink| sink.name == name) {
+            sinks.push(kept.remove(index));
+            continue;
+        }
+        let Some(writer) = open_sink(kind, sink_args, &name, args.max_retries, args.dry_run).await
+        else {
+            continue;
         };
+        let policy = args.queue.policy(&name).await.unwrap_or_else(|e| {
+            log_error!(
+                sink: name,
+                "Error opening the spill file of {}, dropping the logs instead: {}",
+                name,
+                error_chain(&e)
+            );
+            Policy::DropNewest
+        });
+        let (queue, handle) = QueueWriter::new(
+            writer,
+            args.queue.limits(&name, budget),
+            policy,
+            metrics().sink(&name),
+        );
+        handles.push(handle);
+        sinks.push(OpenSink {
+            kind,
+            overflow: args.queue.overflow(&name),
+            max_bytes: args.queue.max_memory_bytes(Some(&name)),
+            name,
+            args: sink_args.clone(),
+            max_retries: args.max_retries,
+            dry_run: args.dry_run,
+            max_items: args.queue.max_memory_items,
+            spill_dir: args.queue.spill_dir.clone(),
+            queue,
+        });
+    }
 
-        let mut writers: Vec<Box<dyn AsyncLogWriter + Send>> = vec![];
-        if let Some((writer, handle)) = AWSLogsWriter::new(&args.aws, args.max_retries)
-            .await
-            .map(|w| QueueWriter::new(w, args.max_memory_items))
-        {
-            writers.push(Box::new(writer));
-            handles.push(handle);
+    let rule_sinks = args.filter.filter.iter().map(|rule| rule.sink());
+    let rule_sinks = rule_sinks.chain(args.limit.limit.iter().map(|rule| rule.sink()));
+    let rule_sinks = rule_sinks.chain(args.queue.overflow.iter().map(|rule| rule.sink()));
+    let rule_sinks = rule_sinks.chain(args.queue.max_memory_bytes.iter().map(|rule| rule.sink()));
+    let rule_sinks = rule_sinks.chain(args.route.route.iter().map(|route| Some(route.sink())));
+    for sink in rule_sinks.flatten() {
+        if !sinks.iter().any(|open| open.name == sink) {
+            log_warn!(sink: sink, "Rule for {} which is not enabled", sink);
         }
+    }
+    sinks
+}
+
+/// Fetches the metadata the logs are enriched with, shared by the pipelines
+/// built until the options change.
+async fn open_enricher(args: &EnrichArgs) -> Option<Arc<Mutex<Enricher>>> {
+    Enricher::new(args)
+        .await
+        .map(|enricher| Arc::new(Mutex::new(enricher)))
+}
+
+/// The processing stages from stdin to stdout and to the queues of the sinks,
+/// the latter taking whole lines.
+struct Pipeline {
+    stdout: Box<dyn AsyncLogWriter + Send>,
+    sinks: LinesWriter<Box<dyn AsyncLogWriter + Send>>,
+}
 
-        if let Some((writer, handle)) =
-            NewRelicWriter::new(&args.newrelic).map(|w| QueueWriter::new(w, args.max_memory_items))
-        {
-            writers.push(Box::new(writer));
-            handles.push(handle);
+#[async_trait]
+impl AsyncLogWriter for Pipeline {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        self.stdout.write_logs(record).await?;
+        self.sinks.write_logs(record).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        let result = self.stdout.flush().await;
+        result.and(self.sinks.flush().await)
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        let result = self.stdout.close().await;
+        result.and(self.sinks.close().await)
+    }
+}
+
+/// Builds the processing stages, returning the idle flush interval they need.
+async fn build_pipeline(
+    args: &LogupArgs,
+    sinks: &[OpenSink],
+    enricher: Option<Arc<Mutex<Enricher>>>,
+) -> (Pipeline, Option<Duration>) {
+    let mut idle_flush = None;
+    let mut writers: Vec<(String, Box<dyn AsyncLogWriter + Send>)> = vec![];
+    for sink in sinks {
+        let name = &sink.name;
+        // filter and limit before queueing so the dropped logs don't take memory
+        let mut writer: Box<dyn AsyncLogWriter + Send> = Box::new(sink.queue.shared());
+        if let Some(limiter) = Limiter::new(&args.limit, name, Instant::now()) {
+            writer = Box::new(LimitWriter::new(writer, limiter, metrics().sink(name)));
+            idle_flush = shortest(idle_flush, SUMMARY_IDLE_FLUSH);
+        }
+        if let Some(filter) = Filter::new(&args.filter.filter, Some(name)) {
+            writer = Box::new(FilterWriter::new(writer, filter));
+        }
+        // an idle input would keep the last batch waiting
+        if matches!(sink.kind, "kinesis" | "firehose") {
+            idle_flush = shortest(idle_flush, MAX_BATCH_DELAY);
         }
+        writers.push((name.clone(), writer));
+    }
 
-        let mut writer = MultiWriter::new(vec![
-            Box::new(tokio::io::stdout()) as Box<dyn AsyncLogWriter + Send>,
-            Box::new(LinesWriter::new(
-                MultiWriter::new(writers),
+    let mut sinks_writer: Box<dyn AsyncLogWriter + Send> = if args.route.route.is_empty() {
+        Box::new(MultiWriter::new(
+            writers.into_iter().map(|(_, writer)| writer).collect(),
+        ))
+    } else {
+        Box::new(RouterWriter::new(&args.route.route, writers))
+    };
+    if let Some(filter) = Filter::new(&args.filter.filter, None) {
+        sinks_writer = Box::new(FilterWriter::new(sinks_writer, filter));
+    }
+    if let Some(deduplicator) = Deduplicator::new(&args.dedup, Instant::now()) {
+        sinks_writer = Box::new(DedupWriter::new(sinks_writer, deduplicator));
+        idle_flush = shortest(idle_flush, SUMMARY_IDLE_FLUSH);
+    }
+    if let Some(redactor) = Redactor::new(&args.redact) {
+        sinks_writer = Box::new(RedactWriter::new(sinks_writer, redactor));
+    }
+    if let Some(extractor) = TimestampExtractor::new(&args.timestamp) {
+        sinks_writer = Box::new(TimestampWriter::new(sinks_writer, extractor));
+    }
+    if let Some(detector) = SeverityDetector::new(&args.severity) {
+        sinks_writer = Box::new(SeverityWriter::new(sinks_writer, detector));
+    }
+    if let Some(enricher) = enricher {
+        sinks_writer = Box::new(EnrichWriter::new(sinks_writer, enricher));
+    }
+    if let Some(parser) = LineParser::new(&args.parse) {
+        sinks_writer = Box::new(ParserWriter::new(sinks_writer, parser));
+    }
+    if let Some(rules) = MultilineRules::new(&args.multiline) {
+        idle_flush = shortest(idle_flush, rules.flush_timeout);
+        sinks_writer = Box::new(MultilineWriter::new(sinks_writer, rules));
+    }
+    if args.strip_ansi {
+        sinks_writer = Box::new(AnsiStripWriter::new(sinks_writer));
+    }
+
+    let mut stdout_writer: Box<dyn AsyncLogWriter + Send> = Box::new(tokio::io::stdout());
+    if args.redact.redact_stdout {
+        if let Some(redactor) = Redactor::new(&args.redact) {
+            // secrets may be split across reads, so redact whole lines
+            stdout_writer = Box::new(LinesWriter::new(
+                RedactWriter::new(stdout_writer, redactor),
                 args.max_line_size,
-            )),
-        ]);
-        read_and_write_loop(&mut reader, &mut writer).await;
+            ));
+            idle_flush = shortest(idle_flush, STDOUT_IDLE_FLUSH);
+        }
     }
 
-    // ensure everything went out of scope at this point, so that tasks can exit
-    for h in handles {
-        // TODO: use a timeout
-        let _ = h.await;
+    let writer = Pipeline {
+        stdout: stdout_writer,
+        sinks: LinesWriter::new(sinks_writer, args.max_line_size),
+    };
+    (writer, idle_flush)
+}
+
+pub async fn run(mut args: LogupArgs) {
+    if let Err(e) = diag::init(&args.diag) {
+        let message = format!("could not open the diagnostics file: {}", error_chain(&e));
+        LogupArgs::command().error(ErrorKind::Io, message).exit();
+    }
+    tokio::spawn(diag::report_suppressed_periodically());
+
+    if let Some(Command::Validate) = args.command {
+        if !validate::validate(&args).await {
+            std::process::exit(1);
+        }
+        return;
     }
+
+    if let Some(addr) = args.metrics_listen {
+        if let Err(e) = metrics::serve(addr).await {
+            log_error!("Error serving the metrics on {}: {}", addr, error_chain(&e));
+        }
+    }
+
+    let (mut reader, source) = match &args.input_file {
+        Some(path) => (
+            Box::new(File::open(path).await.unwrap()) as Box<dyn AsyncLogReader + Send>,
+            path.display().to_string(),
+        ),
+        None => (
+            Box::new(tokio::io::stdin()) as Box<dyn AsyncLogReader + Send>,
+            "stdin".to_string(),
+        ),
+    };
+
+    let mut handles: Vec<JoinHandle<()>> = vec![];
+    let budget = Arc::new(MemoryBudget::new(args.queue.max_memory_bytes(None)));
+    let mut sinks = open_sinks(&args, vec![], &budget, &mut handles).await;
+    let mut enricher = open_enricher(&args.enrich).await;
+    let (mut writer, mut idle_flush) = build_pipeline(&args, &sinks, enricher.clone()).await;
+    let mut signals = Signals::listen();
+    loop {
+        let stop = read_and_write_loop(&mut reader, &mut writer, &source, idle_flush, &mut signals);
+        if stop.await != Stop::Reload {
+            break;
+        }
+
+        let new_args = match LogupArgs::try_parse_with_config() {
+            Ok(new_args) => new_args,
+            Err(e) => {
+                log_error!("Error reloading the configuration: {}", error_message(&e));
+                continue;
+            }
+        };
+        // reopens the diagnostics file, e.g. after logrotate moved it
+        if let Err(e) = diag::init(&new_args.diag) {
+            log_error!("Error reopening the diagnostics: {}", error_chain(&e));
+        }
+        // the stages hand over what they hold, e.g. pending multiline logs,
+        // while the queues are kept open and the partial line goes to the
+        // next stages
+        let partial = writer.sinks.take_partial();
+        let _ = writer.close().await;
+        budget.set_limit(new_args.queue.max_memory_bytes(None));
+        sinks = open_sinks(&new_args, sinks, &budget, &mut handles).await;
+        // rather than fetching the same metadata again
+        if new_args.enrich != args.enrich {
+            enricher = open_enricher(&new_args.enrich).await;
+        }
+        (writer, idle_flush) = build_pipeline(&new_args, &sinks, enricher.clone()).await;
+        writer.sinks.set_partial(partial);
+        args = new_args;
+        log_info!("Configuration reloaded");
+    }
+
+    // deliver the last unterminated line and the pending batches
+    let drained = tokio::time::timeout(Duration::from_secs(args.shutdown_timeout), async {
+        let _ = writer.close().await;
+        for sink in sinks.iter_mut() {
+            let _ = sink.queue.close().await;
+        }
+        for handle in handles.iter_mut() {
+            let _ = handle.await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        // the next run sends the logs of the sinks spilling to disk
+        for sink in sinks.iter() {
+            sink.queue.release_spill().await;
+        }
+        for handle in handles.iter() {
+            handle.abort();
+        }
+    }
+
+    let dropped: u64 = metrics()
+        .sinks()
+        .iter()
+        .map(|m| m.queue_depth.get().max(0) as u64 + m.dropped.get())
+        .sum();
+    if dropped > 0 {
+        log_warn!("{} logs could not be delivered", dropped);
+    }
+    let spilled: i64 = metrics().sinks().iter().map(|m| m.spilled.get()).sum();
+    if spilled > 0 {
+        log_info!("{} logs kept in the spill files for the next run", spilled);
+    }
+    diag::report_suppressed(true);
 }
 
+/// The idle flush interval satisfying every stage that needs one.
+fn shortest(idle_flush: Option<Duration>, interval: Duration) -> Option<Duration> {
+    Some(idle_flush.map_or(interval, |d| d.min(interval)))
+}
+
+/// The signals handled while copying the logs, listened to for the whole run
+/// so that none is missed, e.g. during a reload.
+pub struct Signals {
+    hangup: Signal,
+    interrupt: Signal,
+    terminate: Signal,
+}
+
+impl Signals {
+    pub fn listen() -> Self {
+        Self {
+            hangup: signal(SignalKind::hangup()).unwrap(),
+            interrupt: signal(SignalKind::interrupt()).unwrap(),
+            terminate: signal(SignalKind::terminate()).unwrap(),
+        }
+    }
+}
+
+/// Why the copy of the logs stopped.
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    Eof,
+    /// SIGHUP
+    Reload,
+    /// SIGINT or SIGTERM
+    Shutdown,
+}
+
+/// Copies the logs from the reader to the writer until the end of the input
+/// or a signal, only interrupting the reads.
 pub async fn read_and_write_loop(
     reader: &mut impl AsyncLogReader,
-    writer: &mut impl AsyncLogWriter,
-) {
-    let mut buf: [u8; 1024] = [0; 1024];
-    let mut time: SystemTime = SystemTime::now();
+    writer: &mut (impl AsyncLogWriter + Send),
+    source: &str,
+    idle_flush: Option<Duration>,
+    signals: &mut Signals,
+) -> Stop {
+    let mut record = LogRecord::new(SystemTime::now(), Vec::new());
+    record.source = source.to_string();
 
     loop {
-        let size = reader.read_logs(&mut buf, &mut time).await.unwrap();
+        let read = async {
+            match idle_flush {
+                Some(idle) => tokio::time::timeout(idle, reader.read_logs(&mut record))
+                    .await
+                    .ok(),
+                None => Some(reader.read_logs(&mut record).await),
+            }
+        };
+        let size = tokio::select! {
+            read = read => match read {
+                Some(size) => size.unwrap(),
+                None => {
+                    // send what is pending while the input is idle
+                    writer.flush().await.unwrap();
+                    continue;
+                }
+            },
+            // reading is cancel safe
+            _ = signals.hangup.recv() => return Stop::Reload,
+            _ = signals.interrupt.recv() => return Stop::Shutdown,
+            _ = signals.terminate.recv() => return Stop::Shutdown,
+        };
         if size == 0 {
-            break;
+            return Stop::Eof;
         }
-        writer.write_logs(time, &buf[..size]).await.unwrap();
+        metrics().bytes_read.add(size as u64);
+        // counted here as --redact-stdout splits the lines twice
+        let lines = record.message.iter().filter(|&&b| b == b'\n').count();
+        metrics().lines_read.add(lines as u64);
+        writer.write_logs(&record).await.unwrap();
     }
 }
 
-fn report_err<E>(err: E)
-where
-    E: std::error::Error,
-    E: Send + Sync,
-{
-    eprintln!("[ERROR] {}", err);
-    if let Some(cause) = err.source() {
-        eprintln!("Caused by:");
-        for (i, e) in std::iter::successors(Some(cause), |e| e.source()).enumerate() {
-            eprintln!("   {}: {}", i, e);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::MockAsyncLogWriter;
+    use async_trait::async_trait;
+    use tokio::sync::Notify;
+
+    /// Reads the given chunk, then waits forever as an idle pipe does.
+    struct IdleReader(Option<Vec<u8>>);
+
+    #[async_trait]
+    impl AsyncLogReader for IdleReader {
+        async fn read_logs(&mut self, record: &mut LogRecord) -> std::io::Result<usize> {
+            match self.0.take() {
+                Some(chunk) => {
+                    record.message = chunk;
+                    Ok(record.message.len())
+                }
+                None => std::future::pending().await,
+            }
         }
     }
+
+    #[test]
+    fn config_sinks_ignore_env() {
+        std::env::set_var("KINESIS_STREAM_NAME", "from-env");
+        let sink = |args: &[&str]| parse_config_sink(args.iter().map(OsString::from));
+        // the stream name is required
+        assert!(sink(&["logup", "--kinesis"]).is_err());
+        let args = sink(&["logup", "--kinesis", "--kinesis-stream-name", "audit"]).unwrap();
+        assert_eq!(
+            writer_kinesis::describe(&args.kinesis).unwrap(),
+            "Kinesis Data Stream audit"
+        );
+    }
+
+    fn open_sink(args: &LogupArgs, kind: &'static str, queue: QueueWriter) -> OpenSink {
+        OpenSink {
+            kind,
+            name: kind.to_string(),
+            args: args.sinks.clone(),
+            max_retries: args.max_retries,
+            dry_run: args.dry_run,
+            max_items: args.queue.max_memory_items,
+            max_bytes: None,
+            overflow: Overflow::DropNewest,
+            spill_dir: args.queue.spill_dir.clone(),
+            queue,
+        }
+    }
+
+    #[tokio::test]
+    async fn flush_batches_of_idle_input() {
+        let args =
+            LogupArgs::try_parse_from(["logup", "--kinesis", "--kinesis-stream-name", "logs"])
+                .unwrap();
+        let flushed = Arc::new(Notify::new());
+        let notify = flushed.clone();
+        let mut mock = MockAsyncLogWriter::new();
+        mock.expect_write_logs().times(1).returning(|_| Ok(()));
+        mock.expect_flush().returning(move || {
+            notify.notify_one();
+            Ok(())
+        });
+        let (queue, _) = QueueWriter::new(
+            mock,
+            args.queue.limits("kinesis", &Arc::default()),
+            Policy::DropNewest,
+            Arc::default(),
+        );
+        let sinks = vec![open_sink(&args, "kinesis", queue)];
+        let (mut writer, idle_flush) = build_pipeline(&args, &sinks, None).await;
+        assert_eq!(idle_flush, Some(MAX_BATCH_DELAY));
+
+        // the batch is sent without waiting for more input
+        let mut reader = IdleReader(Some(b"hello\n".to_vec()));
+        let mut signals = Signals::listen();
+        let read = read_and_write_loop(&mut reader, &mut writer, "test", idle_flush, &mut signals);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::select! {
+                _ = read => unreachable!(),
+                _ = flushed.notified() => {}
+            }
+        })
+        .await
+        .unwrap();
+    }
 }
@@ -0,0 +1,420 @@
+use crate::metrics::SinkMetrics;
+use crate::record::{LogRecord, Severity};
+use crate::writer::AsyncLogWriter;
+use async_trait::async_trait;
+use clap::Args;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Limit {
+    /// Token bucket of lines per second.
+    Lines(f64),
+    /// Token bucket of bytes per second.
+    Bytes(f64),
+    /// Keep each log with the given probability.
+    Sample(f64),
+    /// Keep one log every N.
+    Every(u64),
+    /// Logs at or above the level are never limited.
+    ExemptLevel(Severity),
+}
+
+/// A limit, applying to each of the sinks unless a sink is given.
+#[derive(Clone, Debug)]
+pub struct LimitRule {
+    sink: Option<String>,
+    limit: Limit,
+}
+
+impl LimitRule {
+    /// Parses `[SINK:]KIND=VALUE`, e.g. `newrelic:lines=100`.
+    fn parse(rule: &str) -> Result<Self, String> {
+        let (target, value) = rule.split_once('=').ok_or("expected [SINK:]KIND=VALUE")?;
+        let (sink, kind) = match target.split_once(':') {
+            Some((sink, kind)) => (Some(sink.to_string()), kind),
+            None => (None, target),
+        };
+        let positive = |value: &str| match value.parse::<f64>() {
+            Ok(n) if n > 0.0 => Ok(n),
+            _ => Err(format!("expected a positive number, got {}", value)),
+        };
+        let limit = match kind {
+            "lines" => Limit::Lines(positive(value)?),
+            "bytes" => Limit::Bytes(positive(value)?),
+            "sample" => match positive(value)? {
+                ratio if ratio <= 1.0 => Limit::Sample(ratio),
+                _ => return Err("expected a ratio between 0 and 1".to_string()),
+            },
+            "every" => Limit::Every(
+                value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or("expected a positive integer")?,
+            ),
+            "exempt-level" => Limit::ExemptLevel(
+                Severity::parse(value).ok_or_else(|| format!("unknown level {}", value))?,
+            ),
+            _ => {
+                return Err(format!(
+                    "unknown limit {}, expected lines, bytes, sample, every or exempt-level",
+                    kind
+                ))
+            }
+        };
+        Ok(Self { sink, limit })
+    }
+
+    pub fn sink(&self) -> Option<&str> {
+        self.sink.as_deref()
+    }
+}
+
+#[derive(Args)]
+pub struct LimitArgs {
+    #[arg(
+        long,
+        value_name = "[SINK:]KIND=VALUE",
+        value_parser = LimitRule::parse,
+        help = "Limit the logs sent to each sink. KIND is lines or bytes per second, sample with a ratio, every with N to keep one log every N, or exempt-level with the level never limited"
+    )]
+    pub limit: Vec<LimitRule>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "10",
+        help = "Interval of the summary of the logs dropped by the limits, sent to the sink"
+    )]
+    pub limit_summary_interval: u64,
+}
+
+impl LimitArgs {
+    /// Rejects both sampling a sink and keeping one of its logs every N, as
+    /// only one of them would apply.
+    pub fn check(&self) -> Result<(), String> {
+        let every = self
+            .limit
+            .iter()
+            .filter(|r| matches!(r.limit, Limit::Every(_)));
+        for every in every {
+            let sample = self
+                .limit
+                .iter()
+                .filter(|r| matches!(r.limit, Limit::Sample(_)));
+            for sample in sample {
+                match (every.sink(), sample.sink()) {
+                    (Some(a), Some(b)) if a != b => continue,
+                    (Some(sink), _) | (_, Some(sink)) => {
+                        return Err(format!(
+                            "--limit every and sample can't both apply to the {} sink",
+                            sink
+                        ))
+                    }
+                    (None, None) => {
+                        return Err("--limit every and sample can't both apply".to_string())
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Allows a burst of one second worth of tokens.
+    fn new(rate: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last: now,
+        }
+    }
+
+    /// Records costing more than the burst fill up the bucket for longer
+    /// rather than never passing.
+    fn has(&mut self, cost: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(cost));
+        self.last = now;
+        self.tokens >= cost
+    }
+}
+
+pub struct Limiter {
+    lines: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    sample: Option<f64>,
+    every: Option<u64>,
+    exempt_level: Option<Severity>,
+    seen: u64,
+    summary_interval: Duration,
+    summary_start: Instant,
+    dropped: u64,
+}
+
+impl Limiter {
+    /// The limiter of the given sink, with both the rules of all the sinks
+    /// and of the sink itself.
+    pub fn new(args: &LimitArgs, sink: &str, now: Instant) -> Option<Self> {
+        let mut limiter = Self {
+            lines: None,
+            bytes: None,
+            sample: None,
+            every: None,
+            exempt_level: None,
+            seen: 0,
+            summary_interval: Duration::from_secs(args.limit_summary_interval),
+            summary_start: now,
+            dropped: 0,
+        };
+        let rules = args
+            .limit
+            .iter()
+            .filter(|rule| rule.sink.as_deref().is_none_or(|s| s == sink));
+        let mut any = false;
+        for rule in rules {
+            match rule.limit {
+                Limit::Lines(rate) => limiter.lines = Some(TokenBucket::new(rate, now)),
+                Limit::Bytes(rate) => limiter.bytes = Some(TokenBucket::new(rate, now)),
+                Limit::Sample(ratio) => limiter.sample = Some(ratio),
+                Limit::Every(n) => limiter.every = Some(n),
+                Limit::ExemptLevel(level) => limiter.exempt_level = Some(level),
+            }
+            any = true;
+        }
+        any.then_some(limiter)
+    }
+
+    pub fn allow(&mut self, record: &LogRecord, now: Instant) -> bool {
+        if let (Some(exempt), Some(severity)) = (self.exempt_level, record.severity) {
+            if severity >= exempt {
+                return true;
+            }
+        }
+
+        self.seen += 1;
+        let sampled = match (self.every, self.sample) {
+            (Some(n), _) => (self.seen - 1).is_multiple_of(n),
+            (None, Some(ratio)) => random() < ratio,
+            (None, None) => true,
+        };
+        let bytes = record.message.len() as f64;
+        // only take tokens when both buckets have enough
+        let allowed = sampled
+            && self.lines.as_mut().is_none_or(|b| b.has(1.0, now))
+            && self.bytes.as_mut().is_none_or(|b| b.has(bytes, now));
+        if allowed {
+            self.lines.iter_mut().for_each(|b| b.tokens -= 1.0);
+            self.bytes.iter_mut().for_each(|b| b.tokens -= bytes);
+        }
+        if !allowed {
+            self.dropped += 1;
+        }
+        allowed
+    }
+
+    /// Record of the logs dropped since the last summary, once per interval.
+    pub fn summary(&mut self, now: Instant, force: bool) -> Option<LogRecord> {
+        let elapsed = now.saturating_duration_since(self.summary_start);
+        if self.dropped == 0 || (!force && elapsed < self.summary_interval) {
+            return None;
+        }
+        let message = format!(
+            "logup dropped {} logs in the last {}s because of the limits\n",
+            self.dropped,
+            elapsed.as_secs()
+        );
+        self.summary_start = now;
+        self.dropped = 0;
+        let mut record = LogRecord::new(SystemTime::now(), message.into_bytes());
+        record.source = "logup".to_string();
+        Some(record)
+    }
+}
+
+fn random() -> f64 {
+    RandomState::new().hash_one(0) as f64 / u64::MAX as f64
+}
+
+/// Drops the records beyond the limits, sending a summary of them instead.
+pub struct LimitWriter<T: AsyncLogWriter> {
+    inner: T,
+    limiter: Limiter,
+    metrics: Arc<SinkMetrics>,
+}
+
+impl<T: AsyncLogWriter> LimitWriter<T> {
+    pub fn new(inner: T, limiter: Limiter, metrics: Arc<SinkMetrics>) -> Self {
+        Self {
+            inner,
+            limiter,
+            metrics,
+        }
+    }
+}
+
+impl<T: AsyncLogWriter + Send> LimitWriter<T> {
+    async fn write_summary(&mut self, force: bool) -> std::io::Result<()> {
+        match self.limiter.summary(Instant::now(), force) {
+            Some(summary) => self.inner.write_logs(&summary).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: AsyncLogWriter + Send> AsyncLogWriter for LimitWriter<T> {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        self.write_summary(false).await?;
+        if !self.limiter.allow(record, Instant::now()) {
+            self.metrics.limited.inc();
+            return Ok(());
+        }
+        self.inner.write_logs(record).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.write_summary(false).await?;
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        self.write_summary(true).await?;
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::MockAsyncLogWriter;
+
+    fn limiter(rules: &[&str], sink: &str, now: Instant) -> Option<Limiter> {
+        let args = LimitArgs {
+            limit: rules
+                .iter()
+                .map(|rule| LimitRule::parse(rule).unwrap())
+                .collect(),
+            limit_summary_interval: 10,
+        };
+        Limiter::new(&args, sink, now)
+    }
+
+    fn record(message: &str, severity: Option<Severity>) -> LogRecord {
+        let mut record = LogRecord::new(SystemTime::now(), message.as_bytes().to_vec());
+        record.severity = severity;
+        record
+    }
+
+    #[test]
+    fn limit_lines_and_bytes() {
+        let start = Instant::now();
+        let mut aws = limiter(
+            &["lines=2", "aws:bytes=10", "exempt-level=error"],
+            "aws",
+            start,
+        )
+        .unwrap();
+        assert!(aws.allow(&record("1234\n", None), start));
+        assert!(aws.allow(&record("1234\n", None), start));
+        assert!(!aws.allow(&record("1\n", None), start));
+        assert!(aws.allow(&record("failed\n", Some(Severity::Error)), start));
+
+        // half a second refills a line but only 5 bytes
+        let later = start + Duration::from_millis(500);
+        assert!(!aws.allow(&record("123456\n", None), later));
+        assert!(aws.allow(&record("1234\n", None), later));
+
+        assert!(aws.summary(later, false).is_none());
+        let summary = aws.summary(start + Duration::from_secs(10), false).unwrap();
+        assert_eq!(
+            summary.message,
+            b"logup dropped 2 logs in the last 10s because of the limits\n"
+        );
+        assert!(aws
+            .summary(start + Duration::from_secs(20), false)
+            .is_none());
+
+        assert!(limiter(&["aws:lines=1"], "newrelic", start).is_none());
+    }
+
+    #[test]
+    fn limit_below_one_record() {
+        let start = Instant::now();
+        let mut lines = limiter(&["lines=0.5"], "aws", start).unwrap();
+        assert!(!lines.allow(&record("1\n", None), start));
+        assert!(lines.allow(&record("1\n", None), start + Duration::from_secs(1)));
+        assert!(!lines.allow(&record("1\n", None), start + Duration::from_secs(2)));
+        assert!(lines.allow(&record("1\n", None), start + Duration::from_secs(3)));
+
+        // a log longer than the rate passes once its bytes worth of time passed
+        let mut bytes = limiter(&["bytes=4"], "aws", start).unwrap();
+        assert!(!bytes.allow(&record("1234567\n", None), start));
+        assert!(bytes.allow(&record("1234567\n", None), start + Duration::from_secs(2)));
+        assert!(!bytes.allow(&record("1234\n", None), start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn sample_logs() {
+        let now = Instant::now();
+        let mut every = limiter(&["every=3"], "aws", now).unwrap();
+        let kept: Vec<bool> = (0..6)
+            .map(|_| every.allow(&record("x\n", None), now))
+            .collect();
+        assert_eq!(kept, [true, false, false, true, false, false]);
+
+        let mut sample = limiter(&["sample=0.5"], "aws", now).unwrap();
+        let kept = (0..1000)
+            .filter(|_| sample.allow(&record("x\n", None), now))
+            .count();
+        assert!((350..650).contains(&kept));
+
+        assert!(LimitRule::parse("sample=2").is_err());
+        assert!(LimitRule::parse("every=0").is_err());
+        assert!(LimitRule::parse("lines=-1").is_err());
+        assert!(LimitRule::parse("burst=1").is_err());
+
+        let check = |rules: &[&str]| {
+            let args = LimitArgs {
+                limit: rules.iter().map(|r| LimitRule::parse(r).unwrap()).collect(),
+                limit_summary_interval: 10,
+            };
+            args.check()
+        };
+        assert!(check(&["every=3", "aws:sample=0.5"]).is_err());
+        assert!(check(&["newrelic:every=3", "newrelic:sample=0.5"]).is_err());
+        assert!(check(&["newrelic:every=3", "aws:sample=0.5"]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn write_summary_on_close() {
+        let mut mock = MockAsyncLogWriter::new();
+        mock.expect_write_logs()
+            .withf(|record| record.message == b"first\n")
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_write_logs()
+            .withf(|record| record.message.starts_with(b"logup dropped 1 logs"))
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_close().times(1).returning(|| Ok(()));
+
+        let metrics = Arc::new(SinkMetrics::default());
+        let limiter = limiter(&["lines=1"], "aws", Instant::now()).unwrap();
+        let mut writer = LimitWriter::new(mock, limiter, metrics.clone());
+        writer.write_logs(&record("first\n", None)).await.unwrap();
+        writer.write_logs(&record("second\n", None)).await.unwrap();
+        writer.close().await.unwrap();
+        assert_eq!(metrics.limited.get(), 1);
+    }
+}
@@ -1,9 +1,10 @@
-use clap::Parser;
 use logup::LogupArgs;
 
 // Single-thread on purpose to consume the least amount of resources.
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let cli = LogupArgs::parse();
+    let cli = LogupArgs::parse_with_config();
     logup::run(cli).await;
+    // a pending read on stdin can't be cancelled and would block the runtime shutdown
+    std::process::exit(0);
 }
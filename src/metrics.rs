@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicI64, AtomicU64};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Time given to a client to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Relaxed);
+    }
+
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Relaxed);
+    }
+
+    pub fn set(&self, n: i64) {
+        self.0.store(n, Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, le) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if seconds <= le {
+                bucket.fetch_add(1, Relaxed);
+            }
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Relaxed);
+        self.count.fetch_add(1, Relaxed);
+    }
+}
+
+/// Metrics of a single sink, shared by its queue and its writer.
+#[derive(Default)]
+pub struct SinkMetrics {
+    pub name: String,
+    pub lines_written: Counter,
+    pub batches_sent: Counter,
+    pub retries: Counter,
+    pub errors: Counter,
+    pub dropped: Counter,
+    /// Logs dropped on purpose by rate limits and sampling.
+    pub limited: Counter,
+    pub queue_depth: Gauge,
+    pub queue_bytes: Gauge,
+    /// Logs waiting in the spill file.
+    pub spilled: Gauge,
+    /// Time from reading a log to handing it over to the sink.
+    pub latency: Histogram,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub lines_read: Counter,
+    pub bytes_read: Counter,
+    pub deduplicated: Counter,
+    sinks: Mutex<BTreeMap<String, Arc<SinkMetrics>>>,
+}
+
+type SinkCounter = fn(&SinkMetrics) -> &Counter;
+type SinkGauge = fn(&SinkMetrics) -> &Gauge;
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    /// Returns the metrics of the given sink, registering them on first use.
+    pub fn sink(&self, name: &str) -> Arc<SinkMetrics> {
+        self.sinks
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                Arc::new(SinkMetrics {
+                    name: name.to_string(),
+                    ..Default::default()
+                })
+            })
+            .clone()
+    }
+
+    /// The metrics of all the sinks opened so far, including the removed ones.
+    pub fn sinks(&self) -> Vec<Arc<SinkMetrics>> {
+        self.sinks.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Renders all the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        render_header(
+            &mut out,
+            "logup_lines_read_total",
+            "counter",
+            "Lines read from the input",
+        );
+        let _ = writeln!(out, "logup_lines_read_total {}", self.lines_read.get());
+        render_header(
+            &mut out,
+            "logup_bytes_read_total",
+            "counter",
+            "Bytes read from the input",
+        );
+        let _ = writeln!(out, "logup_bytes_read_total {}", self.bytes_read.get());
+        render_header(
+            &mut out,
+            "logup_deduplicated_total",
+            "counter",
+            "Repeated logs collapsed into a summary",
+        );
+        let _ = writeln!(out, "logup_deduplicated_total {}", self.deduplicated.get());
+
+        let sinks = self.sinks.lock().unwrap();
+        let counters: [(&str, &str, SinkCounter); 6] = [
+            ("lines_written_total", "Lines written to the sink", |m| {
+                &m.lines_written
+            }),
+            ("batches_sent_total", "Requests sent to the sink", |m| {
+                &m.batches_sent
+            }),
+            ("retries_total", "Retried requests", |m| &m.retries),
+            ("errors_total", "Failed writes", |m| &m.errors),
+            (
+                "dropped_total",
+                "Logs dropped because of a full queue or a failed write",
+                |m| &m.dropped,
+            ),
+            (
+                "limited_total",
+                "Logs dropped by rate limits and sampling",
+                |m| &m.limited,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let name = format!("logup_sink_{}", name);
+            render_header(&mut out, &name, "counter", help);
+            for (sink, metrics) in sinks.iter() {
+                let sink = escape_label(sink);
+                let _ = writeln!(
+                    out,
+                    "{}{{sink=\"{}\"}} {}",
+                    name,
+                    sink,
+                    counter(metrics).get()
+                );
+            }
+        }
+
+        let gauges: [(&str, &str, SinkGauge); 3] = [
+            ("queue_depth", "Logs waiting in the queue", |m| {
+                &m.queue_depth
+            }),
+            (
+                "queue_bytes",
+                "Approximate memory taken by the logs waiting in the queue",
+                |m| &m.queue_bytes,
+            ),
+            ("spilled", "Logs waiting in the spill file", |m| &m.spilled),
+        ];
+        for (name, help, gauge) in gauges {
+            let name = format!("logup_sink_{}", name);
+            render_header(&mut out, &name, "gauge", help);
+            for (sink, metrics) in sinks.iter() {
+                let sink = escape_label(sink);
+                let _ = writeln!(
+                    out,
+                    "{}{{sink=\"{}\"}} {}",
+                    name,
+                    sink,
+                    gauge(metrics).get()
+                );
+            }
+        }
+
+        let name = "logup_sink_delivery_latency_seconds";
+        render_header(
+            &mut out,
+            name,
+            "histogram",
+            "Time from reading a log to handing it over to the sink",
+        );
+        for (sink, metrics) in sinks.iter() {
+            let sink = escape_label(sink);
+            let latency = &metrics.latency;
+            for (bucket, le) in latency.buckets.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{sink=\"{}\",le=\"{}\"}} {}",
+                    name,
+                    sink,
+                    le,
+                    bucket.load(Relaxed)
+                );
+            }
+            let count = latency.count.load(Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{sink=\"{}\",le=\"+Inf\"}} {}",
+                name, sink, count
+            );
+            let sum = latency.sum_micros.load(Relaxed) as f64 / 1e6;
+            let _ = writeln!(out, "{}_sum{{sink=\"{}\"}} {}", name, sink, sum);
+            let _ = writeln!(out, "{}_count{{sink=\"{}\"}} {}", name, sink, count);
+        }
+        out
+    }
+}
+
+/// Escapes a label value, e.g. a sink name from the configuration file.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Serves the metrics on `GET /metrics` with a minimal HTTP/1.1 server.
+pub async fn serve(addr: SocketAddr) -> std::io::Result<(SocketAddr, JoinHandle<()>)> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    let handle = tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let _ = handle_connection(stream).await;
+            });
+        }
+    });
+    Ok((local_addr, handle))
+}
+
+async fn handle_connection(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let read = async {
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+            let size = stream.read(&mut buf).await?;
+            if size == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..size]);
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    // don't let idle connections pile up
+    tokio::time::timeout(REQUEST_TIMEOUT, read)
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Request timed out"))??;
+
+    let (status, body) = if request.starts_with(b"GET /metrics ") {
+        ("200 OK", metrics().render())
+    } else {
+        ("404 Not Found", "Not Found\n".to_string())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_sink_metrics() {
+        let metrics = Metrics::default();
+        metrics.lines_read.add(3);
+        let sink = metrics.sink("test");
+        sink.lines_written.inc();
+        sink.queue_depth.inc();
+        sink.latency.observe(Duration::from_millis(20));
+        assert!(Arc::ptr_eq(&sink, &metrics.sink("test")));
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE logup_lines_read_total counter\nlogup_lines_read_total 3\n"));
+        assert!(out.contains("logup_sink_lines_written_total{sink=\"test\"} 1\n"));
+        assert!(out.contains("logup_sink_queue_depth{sink=\"test\"} 1\n"));
+
+        metrics.sink("a\"b\\c\nd").errors.inc();
+        let out = metrics.render();
+        assert!(out.contains("logup_sink_errors_total{sink=\"a\\\"b\\\\c\\nd\"} 1\n"));
+        assert!(out
+            .contains("logup_sink_delivery_latency_seconds_bucket{sink=\"test\",le=\"0.01\"} 0\n"));
+        assert!(out.contains(
+            "logup_sink_delivery_latency_seconds_bucket{sink=\"test\",le=\"0.025\"} 1\n"
+        ));
+        assert!(out.contains("logup_sink_delivery_latency_seconds_count{sink=\"test\"} 1\n"));
+        assert!(out.contains("logup_sink_delivery_latency_seconds_sum{sink=\"test\"} 0.02\n"));
+    }
+
+    #[tokio::test]
+    async fn serve_metrics() {
+        let (addr, handle) = serve("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("logup_bytes_read_total"));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        handle.abort();
+    }
+}
@@ -0,0 +1,412 @@
+use crate::parser_logfmt::LogfmtParser;
+use crate::parser_regex::{GrokPattern, RegexParser};
+use crate::record::{LogRecord, Severity};
+use crate::writer::AsyncLogWriter;
+use async_trait::async_trait;
+use chrono::DateTime;
+use clap::{Args, ValueEnum};
+use serde_json::{Map, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum ParseFormat {
+    Json,
+    Logfmt,
+    Regex,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum ParseFallback {
+    // Send the line as it is
+    Keep,
+    // Drop the line
+    Drop,
+}
+
+#[derive(Args)]
+pub struct ParseArgs {
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        help = "Parse each line into structured fields"
+    )]
+    parse: Option<ParseFormat>,
+
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        value_parser = GrokPattern::new,
+        required_if_eq("parse", "regex"),
+        help = "Regex with named captures or %{NAME:field:type} references to grok patterns like NGINXACCESS, the first matching one wins"
+    )]
+    parse_pattern: Vec<GrokPattern>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "keep",
+        help = "What to do with the lines that don't parse"
+    )]
+    parse_fallback: ParseFallback,
+
+    #[arg(
+        long,
+        value_name = "KEYS",
+        value_delimiter = ',',
+        default_value = "message,msg",
+        help = "Parsed keys to use as the message, the first one found wins"
+    )]
+    parse_message_key: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "KEYS",
+        value_delimiter = ',',
+        default_value = "timestamp,time,ts,@timestamp",
+        help = "Parsed keys to use as the timestamp, the first one found wins"
+    )]
+    parse_timestamp_key: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "KEYS",
+        value_delimiter = ',',
+        default_value = "level,severity,lvl",
+        help = "Parsed keys to use as the severity, the first one found wins"
+    )]
+    parse_severity_key: Vec<String>,
+}
+
+/// Extracts the fields of a single line.
+pub trait FieldParser {
+    fn parse(&self, line: &[u8]) -> Option<Map<String, Value>>;
+}
+
+struct JsonParser;
+
+impl FieldParser for JsonParser {
+    fn parse(&self, line: &[u8]) -> Option<Map<String, Value>> {
+        match serde_json::from_slice(line) {
+            Ok(Value::Object(fields)) => Some(fields),
+            _ => None,
+        }
+    }
+}
+
+pub struct Parser {
+    fields: Box<dyn FieldParser + Send + Sync>,
+    fallback: ParseFallback,
+    message_keys: Vec<String>,
+    timestamp_keys: Vec<String>,
+    severity_keys: Vec<String>,
+}
+
+impl Parser {
+    pub fn new(args: &ParseArgs) -> Option<Self> {
+        let fields: Box<dyn FieldParser + Send + Sync> = match args.parse? {
+            ParseFormat::Json => Box::new(JsonParser),
+            ParseFormat::Logfmt => Box::new(LogfmtParser),
+            ParseFormat::Regex => Box::new(RegexParser::new(args.parse_pattern.clone())),
+        };
+        Some(Self {
+            fields,
+            fallback: args.parse_fallback,
+            message_keys: args.parse_message_key.clone(),
+            timestamp_keys: args.parse_timestamp_key.clone(),
+            severity_keys: args.parse_severity_key.clone(),
+        })
+    }
+
+    /// Moves the parsed fields into the record, lifting the well-known keys
+    /// into the message, timestamp and severity. Returns false if the line
+    /// doesn't parse, leaving the record untouched.
+    pub fn parse(&self, record: &mut LogRecord) -> bool {
+        let line = record
+            .message
+            .strip_suffix(b"\n")
+            .unwrap_or(&record.message);
+        let Some(mut fields) = self.fields.parse(line) else {
+            return false;
+        };
+
+        if let Some((key, Value::String(message))) = find_key(&fields, &self.message_keys) {
+            let mut message = message.clone().into_bytes();
+            message.push(b'\n');
+            record.message = message;
+            fields.remove(&key);
+        }
+        if let Some((key, value)) = find_key(&fields, &self.timestamp_keys) {
+            if let Some(timestamp) = parse_timestamp(value) {
+                record.timestamp = timestamp;
+                fields.remove(&key);
+            }
+        }
+        if let Some((key, value)) = find_key(&fields, &self.severity_keys) {
+            if let Some(severity) = parse_severity(value) {
+                record.severity = Some(severity);
+                fields.remove(&key);
+            }
+        }
+        record.attributes.extend(fields);
+        true
+    }
+}
+
+fn find_key<'a>(fields: &'a Map<String, Value>, keys: &[String]) -> Option<(String, &'a Value)> {
+    keys.iter()
+        .find_map(|key| fields.get(key).map(|value| (key.clone(), value)))
+}
+
+/// Accepts RFC 3339 strings and epochs in seconds, millis or nanos.
+fn parse_timestamp(value: &Value) -> Option<SystemTime> {
+    match value {
+        Value::String(s) => match DateTime::parse_from_rfc3339(s) {
+            Ok(time) => Some(time.into()),
+            // epochs of untyped formats such as logfmt
+            Err(_) => parse_timestamp(&Value::Number(s.parse().ok()?)),
+        },
+        // guess the unit from the magnitude
+        Value::Number(n) => {
+            let since_epoch = match n.as_u64() {
+                Some(n) if n >= 100_000_000_000_000_000 => Duration::from_nanos(n),
+                Some(n) if n >= 100_000_000_000_000 => Duration::from_micros(n),
+                Some(n) if n >= 100_000_000_000 => Duration::from_millis(n),
+                Some(n) => Duration::from_secs(n),
+                // fractional seconds
+                None => Duration::try_from_secs_f64(n.as_f64()?).ok()?,
+            };
+            // out of the range of the platform time
+            UNIX_EPOCH.checked_add(since_epoch)
+        }
+        _ => None,
+    }
+}
+
+/// Accepts level names and the numeric levels of bunyan and pino.
+fn parse_severity(value: &Value) -> Option<Severity> {
+    match value {
+        Value::String(s) => Severity::parse(s),
+        Value::Number(n) => match n.as_u64()? {
+            10 => Some(Severity::Trace),
+            20 => Some(Severity::Debug),
+            30 => Some(Severity::Info),
+            40 => Some(Severity::Warn),
+            50 => Some(Severity::Error),
+            60 => Some(Severity::Fatal),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Parses each line into structured fields before handing it over.
+pub struct ParserWriter<T: AsyncLogWriter> {
+    inner: T,
+    parser: Parser,
+}
+
+impl<T: AsyncLogWriter> ParserWriter<T> {
+    pub fn new(inner: T, parser: Parser) -> Self {
+        Self { inner, parser }
+    }
+}
+
+#[async_trait]
+impl<T: AsyncLogWriter + Send> AsyncLogWriter for ParserWriter<T> {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let mut record = record.clone();
+        if !self.parser.parse(&mut record) && self.parser.fallback == ParseFallback::Drop {
+            return Ok(());
+        }
+        self.inner.write_logs(&record).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::MockAsyncLogWriter;
+    use mockall::predicate::eq;
+    use serde_json::json;
+
+    fn args(format: ParseFormat) -> ParseArgs {
+        ParseArgs {
+            parse: Some(format),
+            parse_pattern: vec![],
+            parse_fallback: ParseFallback::Keep,
+            parse_message_key: vec!["message".to_string(), "msg".to_string()],
+            parse_timestamp_key: vec!["timestamp".to_string(), "ts".to_string()],
+            parse_severity_key: vec!["level".to_string()],
+        }
+    }
+
+    #[test]
+    fn lift_json_fields() {
+        let parser = Parser::new(&args(ParseFormat::Json)).unwrap();
+        let time = SystemTime::now();
+
+        let mut record = LogRecord::new(
+            time,
+            br#"{"msg":"started","ts":"2024-02-29T12:34:56.789Z","level":"WARN","user":{"id":1}}"#
+                .to_vec(),
+        );
+        assert!(parser.parse(&mut record));
+        assert_eq!(record.message, b"started\n");
+        assert_eq!(
+            record.timestamp,
+            UNIX_EPOCH + Duration::from_millis(1709210096789)
+        );
+        assert_eq!(record.observed_timestamp, time);
+        assert_eq!(record.severity, Some(Severity::Warn));
+        assert_eq!(record.attributes.len(), 1);
+        assert_eq!(record.attributes["user"], json!({"id": 1}));
+
+        let mut record = LogRecord::new(
+            time,
+            b"{\"timestamp\":1709210096789,\"level\":50}\n".to_vec(),
+        );
+        assert!(parser.parse(&mut record));
+        assert_eq!(
+            record.timestamp,
+            UNIX_EPOCH + Duration::from_millis(1709210096789)
+        );
+        assert_eq!(record.severity, Some(Severity::Error));
+        // without a message key the line is kept as it is
+        assert_eq!(
+            record.message,
+            b"{\"timestamp\":1709210096789,\"level\":50}\n"
+        );
+        assert!(record.attributes.is_empty());
+
+        let mut record = LogRecord::new(time, b"{\"ts\":1709210096789012}\n".to_vec());
+        assert!(parser.parse(&mut record));
+        assert_eq!(
+            record.timestamp,
+            UNIX_EPOCH + Duration::from_micros(1709210096789012)
+        );
+    }
+
+    #[test]
+    fn keep_unparsed_lines() {
+        let parser = Parser::new(&args(ParseFormat::Json)).unwrap();
+        for line in [&b"plain text\n"[..], b"[1, 2]\n", b"{\"broken\n"] {
+            let mut record = LogRecord::new(SystemTime::now(), line.to_vec());
+            let expected = record.clone();
+            assert!(!parser.parse(&mut record));
+            assert_eq!(record, expected);
+        }
+
+        // invalid values of well-known keys stay as attributes
+        let mut record = LogRecord::new(
+            SystemTime::now(),
+            br#"{"message":1,"timestamp":"yesterday","level":"loud"}"#.to_vec(),
+        );
+        assert!(parser.parse(&mut record));
+        assert_eq!(record.severity, None);
+        assert_eq!(record.attributes.len(), 3);
+
+        // and so do epochs beyond the range of the platform time
+        let mut record = LogRecord::new(SystemTime::now(), br#"{"ts":1e19}"#.to_vec());
+        assert!(parser.parse(&mut record));
+        assert_eq!(record.attributes["ts"], json!(1e19));
+
+        let parser = Parser::new(&args(ParseFormat::Logfmt)).unwrap();
+        let mut record = LogRecord::new(SystemTime::now(), b"ts=1e19\n".to_vec());
+        assert!(parser.parse(&mut record));
+        assert_eq!(record.attributes["ts"], json!("1e19"));
+    }
+
+    #[test]
+    fn lift_logfmt_fields() {
+        let parser = Parser::new(&args(ParseFormat::Logfmt)).unwrap();
+        let mut record = LogRecord::new(
+            SystemTime::now(),
+            b"ts=1709210096 level=error msg=\"disk full\" path=/var\n".to_vec(),
+        );
+        assert!(parser.parse(&mut record));
+        assert_eq!(record.message, b"disk full\n");
+        assert_eq!(
+            record.timestamp,
+            UNIX_EPOCH + Duration::from_secs(1709210096)
+        );
+        assert_eq!(record.severity, Some(Severity::Error));
+        assert_eq!(record.attributes["path"], json!("/var"));
+    }
+
+    #[test]
+    fn lift_regex_fields() {
+        let mut args = args(ParseFormat::Regex);
+        args.parse_pattern = vec![GrokPattern::new(
+            r"%{TIMESTAMP_ISO8601:timestamp} %{LOGLEVEL:level} %{GREEDYDATA:message}",
+        )
+        .unwrap()];
+        let parser = Parser::new(&args).unwrap();
+        let mut record = LogRecord::new(
+            SystemTime::now(),
+            b"2024-02-29T12:34:56Z WARN disk almost full\n".to_vec(),
+        );
+        assert!(parser.parse(&mut record));
+        assert_eq!(record.message, b"disk almost full\n");
+        assert_eq!(
+            record.timestamp,
+            UNIX_EPOCH + Duration::from_secs(1709210096)
+        );
+        assert_eq!(record.severity, Some(Severity::Warn));
+        assert!(record.attributes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn write_parsed_records() {
+        let mut mock = MockAsyncLogWriter::new();
+        let time = SystemTime::now();
+
+        let mut expected = LogRecord::new(time, b"hello\n".to_vec());
+        expected.attributes.insert("user".to_string(), json!("bob"));
+        mock.expect_write_logs()
+            .with(eq(expected))
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_close().times(1).returning(|| Ok(()));
+
+        let mut writer = ParserWriter::new(mock, Parser::new(&args(ParseFormat::Json)).unwrap());
+        writer
+            .write_logs(&LogRecord::new(
+                time,
+                b"{\"message\":\"hello\",\"user\":\"bob\"}\n".to_vec(),
+            ))
+            .await
+            .unwrap();
+        writer.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_unparsed_records() {
+        let mut mock = MockAsyncLogWriter::new();
+        let time = SystemTime::now();
+
+        mock.expect_write_logs()
+            .with(eq(LogRecord::new(time, b"{}\n".to_vec())))
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_write_logs().times(0);
+
+        let mut args = args(ParseFormat::Json);
+        args.parse_fallback = ParseFallback::Drop;
+        let mut writer = ParserWriter::new(mock, Parser::new(&args).unwrap());
+        for line in [&b"not json\n"[..], b"{}\n"] {
+            writer
+                .write_logs(&LogRecord::new(time, line.to_vec()))
+                .await
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use crate::parser::FieldParser;
+use serde_json::{Map, Value};
+
+/// Parses `key=value` pairs separated by spaces, where values may be double
+/// quoted with backslash escapes and keys without a value are true.
+pub struct LogfmtParser;
+
+impl FieldParser for LogfmtParser {
+    fn parse(&self, line: &[u8]) -> Option<Map<String, Value>> {
+        let line = std::str::from_utf8(line).ok()?;
+        let mut chars = line.chars().peekable();
+        let mut fields = Map::new();
+        let mut has_value = false;
+
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.peek().is_none() {
+                break;
+            }
+
+            let mut key = String::new();
+            while let Some(c) = chars.next_if(|&c| c != '=' && !c.is_whitespace()) {
+                key.push(c);
+            }
+            if key.is_empty() {
+                return None;
+            }
+            if chars.next_if_eq(&'=').is_none() {
+                fields.insert(key, Value::Bool(true));
+                continue;
+            }
+
+            let mut value = String::new();
+            if chars.next_if_eq(&'"').is_some() {
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            'n' => value.push('\n'),
+                            't' => value.push('\t'),
+                            'r' => value.push('\r'),
+                            c => value.push(c),
+                        },
+                        c => value.push(c),
+                    }
+                }
+            } else {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    value.push(c);
+                }
+            }
+            fields.insert(key, Value::String(value));
+            has_value = true;
+        }
+
+        // plain text would otherwise be parsed as a list of keys
+        has_value.then_some(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(line: &str) -> Option<Value> {
+        LogfmtParser.parse(line.as_bytes()).map(Value::Object)
+    }
+
+    #[test]
+    fn parse_pairs() {
+        assert_eq!(
+            parse(
+                r#"ts=2024-02-29T12:34:56Z level=info msg="user \"bob\" logged in" retry debug= path=/a=b"#
+            ),
+            Some(json!({
+                "ts": "2024-02-29T12:34:56Z",
+                "level": "info",
+                "msg": "user \"bob\" logged in",
+                "retry": true,
+                "debug": "",
+                "path": "/a=b",
+            }))
+        );
+        assert_eq!(
+            parse("  a=1\tb=\"x\\ny\"  "),
+            Some(json!({"a": "1", "b": "x\ny"}))
+        );
+    }
+
+    #[test]
+    fn reject_other_lines() {
+        assert_eq!(parse("plain text"), None);
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("msg=\"unterminated"), None);
+        assert_eq!(parse("=value"), None);
+    }
+}
@@ -0,0 +1,276 @@
+use crate::parser::FieldParser;
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::sync::LazyLock;
+
+/// Library of patterns that can be referenced as `%{NAME}` or `%{NAME:field}`.
+const GROK_PATTERNS: &[(&str, &str)] = &[
+    ("INT", r"[+-]?\d+"),
+    ("NUMBER", r"[+-]?(?:\d+(?:\.\d*)?|\.\d+)"),
+    ("WORD", r"\w+"),
+    ("NOTSPACE", r"\S+"),
+    ("SPACE", r"\s*"),
+    ("DATA", r".*?"),
+    ("GREEDYDATA", r".*"),
+    ("QUOTEDSTRING", r#""(?:[^"\\]|\\.)*""#),
+    ("IPV4", r"(?:\d{1,3}\.){3}\d{1,3}"),
+    ("IPV6", r"[0-9A-Fa-f]*:[0-9A-Fa-f:.]+"),
+    ("IP", "%{IPV6}|%{IPV4}"),
+    ("HOSTNAME", r"[0-9A-Za-z][0-9A-Za-z.-]*"),
+    ("IPORHOST", "%{IP}|%{HOSTNAME}"),
+    ("USER", r"[0-9A-Za-z._@-]+"),
+    (
+        "DURATION",
+        r"(?:\d+(?:\.\d+)?(?:ns|us|µs|ms|s|m|h))+|\d+(?:\.\d+)?",
+    ),
+    ("HTTPDATE", r"\d{2}/\w{3}/\d{4}:\d{2}:\d{2}:\d{2} [+-]\d{4}"),
+    (
+        "TIMESTAMP_ISO8601",
+        r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d+)?(?:Z|[+-]\d{2}:?\d{2})?",
+    ),
+    (
+        "LOGLEVEL",
+        r"(?i:trace|debug|info|notice|warn(?:ing)?|err(?:or)?|crit(?:ical)?|fatal|panic)",
+    ),
+    (
+        "COMMONAPACHELOG",
+        r#"%{IPORHOST:client} %{USER:ident} %{USER:auth} \[%{HTTPDATE:timestamp}\] "(?:%{WORD:method} %{NOTSPACE:path}(?: HTTP/%{NUMBER:http_version})?|%{DATA:request})" %{INT:status:int} (?:%{INT:bytes:int}|-)"#,
+    ),
+    (
+        "COMBINEDAPACHELOG",
+        r#"%{COMMONAPACHELOG} "%{DATA:referrer}" "%{DATA:user_agent}""#,
+    ),
+    ("NGINXACCESS", "%{COMBINEDAPACHELOG}"),
+];
+
+static GROK_REFERENCE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"%\{(\w+)(?::([\w.@-]+))?(?::(\w+))?\}").unwrap());
+
+/// Deep enough for the library, bounded against self references.
+const MAX_EXPANSION_DEPTH: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum FieldType {
+    String,
+    Int,
+    Float,
+    /// Converted to seconds.
+    Duration,
+}
+
+impl FieldType {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "string" => Ok(FieldType::String),
+            "int" => Ok(FieldType::Int),
+            "float" => Ok(FieldType::Float),
+            "duration" => Ok(FieldType::Duration),
+            _ => Err(format!(
+                "unknown type {}, expected string, int, float or duration",
+                name
+            )),
+        }
+    }
+
+    /// Falls back to the string if the conversion fails.
+    fn convert(&self, value: &str) -> Value {
+        let converted = match self {
+            FieldType::String => None,
+            FieldType::Int => value.parse::<i64>().ok().map(Value::from),
+            FieldType::Float => value.parse::<f64>().ok().map(Value::from),
+            FieldType::Duration => parse_duration_secs(value).map(Value::from),
+        };
+        converted.unwrap_or_else(|| value.into())
+    }
+}
+
+/// Parses durations such as `1h30m`, `250ms` or `0.25`, in seconds when unitless.
+fn parse_duration_secs(value: &str) -> Option<f64> {
+    if let Ok(secs) = value.parse::<f64>() {
+        return Some(secs);
+    }
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .filter(|&len| len > 0)?;
+        let number = rest[..number_len].parse::<f64>().ok()?;
+        rest = &rest[number_len..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let scale = match &rest[..unit_len] {
+            "ns" => 1e-9,
+            "us" | "µs" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return None,
+        };
+        total += number * scale;
+        rest = &rest[unit_len..];
+    }
+    Some(total)
+}
+
+/// A regex with named captures, where `%{NAME:field:type}` references to the
+/// pattern library are expanded and captured as typed fields.
+#[derive(Clone)]
+pub struct GrokPattern {
+    regex: Regex,
+    // group name, field name and type of each capture
+    fields: Vec<(String, String, FieldType)>,
+}
+
+impl GrokPattern {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let mut fields = vec![];
+        let expanded = expand(pattern, Some(&mut fields), 0)?;
+        let regex = Regex::new(&format!("^(?:{})$", expanded)).map_err(|e| e.to_string())?;
+
+        // plain named captures are strings
+        for name in regex.capture_names().flatten() {
+            if !fields.iter().any(|(group, _, _)| group == name) {
+                fields.push((name.to_string(), name.to_string(), FieldType::String));
+            }
+        }
+        Ok(Self { regex, fields })
+    }
+
+    fn captures(&self, line: &str) -> Option<Map<String, Value>> {
+        let captures = self.regex.captures(line)?;
+        let mut result = Map::new();
+        for (group, field, field_type) in &self.fields {
+            if let Some(value) = captures.name(group) {
+                result.insert(field.clone(), field_type.convert(value.as_str()));
+            }
+        }
+        Some(result)
+    }
+}
+
+/// Replaces the references with the library patterns, capturing the named
+/// ones in groups called `grokN` unless `fields` is None.
+fn expand(
+    pattern: &str,
+    mut fields: Option<&mut Vec<(String, String, FieldType)>>,
+    depth: usize,
+) -> Result<String, String> {
+    if depth > MAX_EXPANSION_DEPTH {
+        return Err("patterns nested too deeply".to_string());
+    }
+    let mut result = String::new();
+    let mut last = 0;
+    for reference in GROK_REFERENCE.captures_iter(pattern) {
+        let whole = reference.get(0).unwrap();
+        result.push_str(&pattern[last..whole.start()]);
+        last = whole.end();
+
+        let name = &reference[1];
+        let (_, library_pattern) = GROK_PATTERNS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .ok_or_else(|| format!("unknown pattern {}", name))?;
+        let field_type = match reference.get(3) {
+            Some(t) => FieldType::parse(t.as_str())?,
+            None => FieldType::String,
+        };
+        match (reference.get(2), fields.as_deref_mut()) {
+            (Some(field), Some(fields)) => {
+                let group = format!("grok{}", fields.len());
+                fields.push((group.clone(), field.as_str().to_string(), field_type));
+                // the fields of the inner references are not captured
+                let inner = expand(library_pattern, None, depth + 1)?;
+                result.push_str(&format!("(?P<{}>{})", group, inner));
+            }
+            (_, fields) => {
+                let inner = expand(library_pattern, fields, depth + 1)?;
+                result.push_str(&format!("(?:{})", inner));
+            }
+        }
+    }
+    result.push_str(&pattern[last..]);
+    Ok(result)
+}
+
+/// Extracts fields with the first of the patterns matching the whole line.
+pub struct RegexParser {
+    patterns: Vec<GrokPattern>,
+}
+
+impl RegexParser {
+    pub fn new(patterns: Vec<GrokPattern>) -> Self {
+        Self { patterns }
+    }
+}
+
+impl FieldParser for RegexParser {
+    fn parse(&self, line: &[u8]) -> Option<Map<String, Value>> {
+        let line = std::str::from_utf8(line).ok()?;
+        self.patterns
+            .iter()
+            .find_map(|pattern| pattern.captures(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(patterns: &[&str], line: &str) -> Option<Value> {
+        let patterns = patterns
+            .iter()
+            .map(|p| GrokPattern::new(p).unwrap())
+            .collect();
+        RegexParser::new(patterns)
+            .parse(line.as_bytes())
+            .map(Value::Object)
+    }
+
+    #[test]
+    fn parse_combined_access_log() {
+        let line = r#"10.0.0.1 - bob [29/Feb/2024:12:34:56 +0000] "GET /index.html HTTP/1.1" 200 512 "-" "curl/8.0""#;
+        assert_eq!(
+            parse(&["%{NGINXACCESS}"], line),
+            Some(json!({
+                "client": "10.0.0.1",
+                "ident": "-",
+                "auth": "bob",
+                "timestamp": "29/Feb/2024:12:34:56 +0000",
+                "method": "GET",
+                "path": "/index.html",
+                "http_version": "1.1",
+                "status": 200,
+                "bytes": 512,
+                "referrer": "-",
+                "user_agent": "curl/8.0",
+            }))
+        );
+    }
+
+    #[test]
+    fn convert_types_and_fallback() {
+        let patterns = [
+            r"%{LOGLEVEL:level} took %{DURATION:took:duration} ratio=%{NUMBER:ratio:float} (?P<rest>.*)",
+            r"%{WORD:word}",
+        ];
+        assert_eq!(
+            parse(&patterns, "INFO took 1m30.5s ratio=0.25 done"),
+            Some(json!({"level": "INFO", "took": 90.5, "ratio": 0.25, "rest": "done"}))
+        );
+        assert_eq!(parse(&patterns, "hello"), Some(json!({"word": "hello"})));
+        assert_eq!(parse(&patterns, "no match here"), None);
+    }
+
+    #[test]
+    fn reject_invalid_patterns() {
+        assert!(GrokPattern::new("%{NOPE:x}").is_err());
+        assert!(GrokPattern::new("%{INT:x:bool}").is_err());
+        assert!(GrokPattern::new("(unclosed").is_err());
+        assert_eq!(parse_duration_secs("250ms"), Some(0.25));
+        assert_eq!(parse_duration_secs("1h2x"), None);
+    }
+}
@@ -1,37 +1,48 @@
+use crate::record::LogRecord;
 use async_trait::async_trait;
 use std::io::Error;
 use std::time::SystemTime;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, Stdin};
+use tokio::io::{AsyncRead, AsyncReadExt, Stdin};
+
+const READ_SIZE: usize = 1024;
 
 #[async_trait]
 pub trait AsyncLogReader {
-    async fn read_logs(
-        &mut self,
-        buf: &mut [u8],
-        time: &mut SystemTime,
-    ) -> Result<usize, std::io::Error>;
+    /// Replaces the message of the record with the next chunk of input,
+    /// returning its size or 0 at EOF. Cancelling it must not lose input.
+    async fn read_logs(&mut self, record: &mut LogRecord) -> Result<usize, std::io::Error>;
+}
+
+async fn read_chunk<R: AsyncRead + Unpin + Send>(
+    reader: &mut R,
+    record: &mut LogRecord,
+) -> Result<usize, Error> {
+    record.message.resize(READ_SIZE, 0);
+    let size = reader.read(&mut record.message).await?;
+    record.message.truncate(size);
+    record.timestamp = SystemTime::now();
+    record.observed_timestamp = record.timestamp;
+    Ok(size)
 }
 
 #[async_trait]
 impl AsyncLogReader for Stdin {
-    async fn read_logs(&mut self, buf: &mut [u8], time: &mut SystemTime) -> Result<usize, Error> {
-        *time = SystemTime::now();
-        self.read(buf).await
+    async fn read_logs(&mut self, record: &mut LogRecord) -> Result<usize, Error> {
+        read_chunk(self, record).await
     }
 }
 
 #[async_trait]
 impl AsyncLogReader for File {
-    async fn read_logs(&mut self, buf: &mut [u8], time: &mut SystemTime) -> Result<usize, Error> {
-        *time = SystemTime::now();
-        self.read(buf).await
+    async fn read_logs(&mut self, record: &mut LogRecord) -> Result<usize, Error> {
+        read_chunk(self, record).await
     }
 }
 
 #[async_trait]
 impl<T: AsyncLogReader + Send + ?Sized> AsyncLogReader for Box<T> {
-    async fn read_logs(&mut self, buf: &mut [u8], time: &mut SystemTime) -> Result<usize, Error> {
-        (**self).read_logs(buf, time).await
+    async fn read_logs(&mut self, record: &mut LogRecord) -> Result<usize, Error> {
+        (**self).read_logs(record).await
     }
 }
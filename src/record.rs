@@ -0,0 +1,196 @@
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Severity {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Fatal,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Trace => "trace",
+            Severity::Debug => "debug",
+            Severity::Info => "info",
+            Severity::Warn => "warn",
+            Severity::Error => "error",
+            Severity::Fatal => "fatal",
+        }
+    }
+
+    /// Parses the usual level names, case insensitive.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "trace" => Some(Severity::Trace),
+            "debug" => Some(Severity::Debug),
+            "info" | "information" | "notice" => Some(Severity::Info),
+            "warn" | "warning" => Some(Severity::Warn),
+            "error" | "err" => Some(Severity::Error),
+            "fatal" | "critical" | "crit" | "alert" | "emerg" | "panic" => Some(Severity::Fatal),
+            _ => None,
+        }
+    }
+
+    /// Severity level as defined by syslog, also used by journald's PRIORITY.
+    pub fn syslog_severity(&self) -> u8 {
+        match self {
+            Severity::Trace | Severity::Debug => 7,
+            Severity::Info => 6,
+            Severity::Warn => 4,
+            Severity::Error => 3,
+            Severity::Fatal => 2,
+        }
+    }
+}
+
+/// A single log event flowing from the reader down to the sinks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogRecord {
+    /// When the event happened.
+    pub timestamp: SystemTime,
+    /// When logup read the event.
+    pub observed_timestamp: SystemTime,
+    pub message: Vec<u8>,
+    pub severity: Option<Severity>,
+    pub attributes: BTreeMap<String, Value>,
+    /// Where the event was read from, e.g. stdin or the input file path.
+    pub source: String,
+}
+
+impl LogRecord {
+    pub fn new(time: SystemTime, message: Vec<u8>) -> Self {
+        Self {
+            timestamp: time,
+            observed_timestamp: time,
+            message,
+            severity: None,
+            attributes: BTreeMap::new(),
+            source: String::new(),
+        }
+    }
+
+    /// Creates a record with the same metadata but a different message.
+    pub fn with_message(&self, message: Vec<u8>) -> Self {
+        Self {
+            timestamp: self.timestamp,
+            observed_timestamp: self.observed_timestamp,
+            message,
+            severity: self.severity,
+            attributes: self.attributes.clone(),
+            source: self.source.clone(),
+        }
+    }
+
+    pub fn timestamp_millis(&self) -> i64 {
+        self.timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64)
+    }
+
+    pub fn message_lossy(&self) -> String {
+        let message = self.message.strip_suffix(b"\n").unwrap_or(&self.message);
+        String::from_utf8_lossy(message).to_string()
+    }
+
+    /// Whether the record carries nothing besides the message, in which case
+    /// sinks without native attributes can send the raw line.
+    pub fn is_plain(&self) -> bool {
+        self.severity.is_none() && self.attributes.is_empty()
+    }
+
+    /// Approximate memory taken by the record.
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.message.len()
+            + self.source.len()
+            + self
+                .attributes
+                .iter()
+                .map(|(key, value)| key.len() + value_size(value))
+                .sum::<usize>()
+    }
+
+    /// JSON object with the attributes and the well-known fields, for sinks
+    /// without a native notion of attributes.
+    pub fn to_json(&self) -> Value {
+        let mut json = Map::new();
+        for (key, value) in &self.attributes {
+            json.insert(key.clone(), value.clone());
+        }
+        json.insert("timestamp".to_string(), self.timestamp_millis().into());
+        json.insert("message".to_string(), self.message_lossy().into());
+        if let Some(severity) = self.severity {
+            json.insert("level".to_string(), severity.as_str().into());
+        }
+        if !self.source.is_empty() {
+            json.insert("source".to_string(), self.source.clone().into());
+        }
+        Value::Object(json)
+    }
+
+    /// The raw message for plain records, otherwise the JSON representation.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.is_plain() {
+            self.message.clone()
+        } else {
+            let mut bytes = self.to_json().to_string().into_bytes();
+            bytes.push(b'\n');
+            bytes
+        }
+    }
+}
+
+fn value_size(value: &Value) -> usize {
+    std::mem::size_of::<Value>()
+        + match value {
+            Value::String(s) => s.len(),
+            Value::Array(values) => values.iter().map(value_size).sum(),
+            Value::Object(map) => map
+                .iter()
+                .map(|(key, value)| key.len() + value_size(value))
+                .sum(),
+            _ => 0,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn serialize_record() {
+        let mut record = LogRecord::new(UNIX_EPOCH + Duration::from_millis(42), b"foo\n".to_vec());
+        record.source = "stdin".to_string();
+        assert!(record.is_plain());
+        assert_eq!(record.to_bytes(), b"foo\n");
+
+        record.severity = Some(Severity::Warn);
+        record.attributes.insert("user".to_string(), json!("bob"));
+        record
+            .attributes
+            .insert("message".to_string(), json!("ignored"));
+        assert_eq!(
+            record.to_json(),
+            json!({
+                "timestamp": 42,
+                "message": "foo",
+                "level": "warn",
+                "source": "stdin",
+                "user": "bob",
+            })
+        );
+        assert_eq!(
+            record.to_bytes(),
+            format!("{}\n", record.to_json()).as_bytes()
+        );
+    }
+}
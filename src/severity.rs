@@ -0,0 +1,196 @@
+use crate::record::{LogRecord, Severity};
+use crate::writer::AsyncLogWriter;
+use async_trait::async_trait;
+use clap::Args;
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// Level tokens as printed by common loggers, the leftmost one wins.
+static BUILTIN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(
+        r"(?P<fatal>\b(?:FATAL|CRITICAL|CRIT|PANIC|EMERG|EMERGENCY|ALERT)\b|\[[FC]\]|^F\d{4} |panicked at|^panic: )",
+        r"|(?P<error>\b(?:ERROR|ERR)\b|\[E\]|^E\d{4} |^Traceback \(most recent call last\):)",
+        r"|(?P<warn>\b(?:WARN|WARNING)\b|\[W\]|^W\d{4} )",
+        r"|(?P<info>\b(?:INFO|NOTICE)\b|\[I\]|^I\d{4} )",
+        r"|(?P<debug>\bDEBUG\b|\[D\])",
+        r"|(?P<trace>\bTRACE\b|\[T\])",
+    ))
+    .unwrap()
+});
+
+const BUILTIN_GROUPS: [(&str, Severity); 6] = [
+    ("fatal", Severity::Fatal),
+    ("error", Severity::Error),
+    ("warn", Severity::Warn),
+    ("info", Severity::Info),
+    ("debug", Severity::Debug),
+    ("trace", Severity::Trace),
+];
+
+fn parse_pattern(pattern: &str) -> Result<(Severity, Regex), String> {
+    let (level, regex) = pattern.split_once('=').ok_or("expected LEVEL=REGEX")?;
+    let severity = Severity::parse(level).ok_or_else(|| format!("unknown level {}", level))?;
+    let regex = Regex::new(regex).map_err(|e| e.to_string())?;
+    Ok((severity, regex))
+}
+
+#[derive(Args)]
+pub struct SeverityArgs {
+    #[arg(
+        long,
+        help = "Detect the severity of logs from level tokens such as ERROR, [W] or panicked at"
+    )]
+    severity_detect: bool,
+
+    #[arg(
+        long,
+        value_name = "LEVEL=REGEX",
+        value_parser = parse_pattern,
+        help = "Logs matching the regex have the given severity, checked before the level tokens"
+    )]
+    severity_pattern: Vec<(Severity, Regex)>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "LEVEL",
+        help = "Severity of the logs without one, e.g. error when reading the stderr of a process"
+    )]
+    severity_default: Option<Severity>,
+}
+
+pub struct SeverityDetector {
+    builtin: bool,
+    patterns: Vec<(Severity, Regex)>,
+    default: Option<Severity>,
+}
+
+impl SeverityDetector {
+    pub fn new(args: &SeverityArgs) -> Option<Self> {
+        if !args.severity_detect
+            && args.severity_pattern.is_empty()
+            && args.severity_default.is_none()
+        {
+            return None;
+        }
+        Some(Self {
+            builtin: args.severity_detect,
+            patterns: args.severity_pattern.clone(),
+            default: args.severity_default,
+        })
+    }
+
+    /// Sets the severity of the record unless a parser already did.
+    pub fn detect(&self, record: &mut LogRecord) {
+        if record.severity.is_some() {
+            return;
+        }
+        let message = String::from_utf8_lossy(&record.message);
+        record.severity = self
+            .patterns
+            .iter()
+            .find(|(_, regex)| regex.is_match(&message))
+            .map(|(severity, _)| *severity)
+            .or_else(|| self.builtin.then(|| detect_builtin(&message)).flatten())
+            .or(self.default);
+    }
+}
+
+fn detect_builtin(message: &str) -> Option<Severity> {
+    let captures = BUILTIN.captures(message)?;
+    BUILTIN_GROUPS
+        .iter()
+        .find(|(group, _)| captures.name(group).is_some())
+        .map(|(_, severity)| *severity)
+}
+
+/// Sets the severity of each record from its content.
+pub struct SeverityWriter<T: AsyncLogWriter> {
+    inner: T,
+    detector: SeverityDetector,
+}
+
+impl<T: AsyncLogWriter> SeverityWriter<T> {
+    pub fn new(inner: T, detector: SeverityDetector) -> Self {
+        Self { inner, detector }
+    }
+}
+
+#[async_trait]
+impl<T: AsyncLogWriter + Send> AsyncLogWriter for SeverityWriter<T> {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let mut record = record.clone();
+        self.detector.detect(&mut record);
+        self.inner.write_logs(&record).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn detect(detector: &SeverityDetector, line: &str) -> Option<Severity> {
+        let mut record = LogRecord::new(SystemTime::now(), line.as_bytes().to_vec());
+        detector.detect(&mut record);
+        record.severity
+    }
+
+    #[test]
+    fn detect_level_tokens() {
+        let detector = SeverityDetector {
+            builtin: true,
+            patterns: vec![],
+            default: None,
+        };
+        assert_eq!(
+            detect(&detector, "2024-02-29 ERROR disk full\n"),
+            Some(Severity::Error)
+        );
+        assert_eq!(detect(&detector, "[W] low memory\n"), Some(Severity::Warn));
+        assert_eq!(
+            detect(&detector, "E0229 12:34:56.789 main.go:12] failed\n"),
+            Some(Severity::Error)
+        );
+        assert_eq!(
+            detect(&detector, "thread 'main' panicked at src/main.rs:2:5:\n"),
+            Some(Severity::Fatal)
+        );
+        // the leftmost token is the level, the rest is the message
+        assert_eq!(
+            detect(&detector, "INFO retried after ERROR\n"),
+            Some(Severity::Info)
+        );
+        assert_eq!(detect(&detector, "no error here\n"), None);
+    }
+
+    #[test]
+    fn apply_patterns_and_default() {
+        let detector = SeverityDetector {
+            builtin: true,
+            patterns: vec![parse_pattern("debug=GET /health").unwrap()],
+            default: Some(Severity::Warn),
+        };
+        assert_eq!(
+            detect(&detector, "INFO GET /health 200\n"),
+            Some(Severity::Debug)
+        );
+        assert_eq!(detect(&detector, "plain\n"), Some(Severity::Warn));
+
+        let mut record = LogRecord::new(SystemTime::now(), b"ERROR\n".to_vec());
+        record.severity = Some(Severity::Info);
+        detector.detect(&mut record);
+        assert_eq!(record.severity, Some(Severity::Info));
+
+        assert!(parse_pattern("loud=x").is_err());
+        assert!(parse_pattern("error").is_err());
+    }
+}
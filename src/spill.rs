@@ -0,0 +1,267 @@
+use crate::record::{LogRecord, Severity};
+use serde_json::{json, Map, Value};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Records written to a file while the queue of a sink is full, read back in
+/// order once it has room. The file survives restarts, so the records left at
+/// shutdown are sent by the next run, and is locked so that only one process
+/// uses it at a time. The records read back stay in the file until all are,
+/// so a crash sends them again.
+///
+/// The file is written and read on the blocking threads, one operation at a
+/// time, so that a slow disk doesn't hold up the input and the other sinks.
+#[derive(Clone)]
+pub struct Spill {
+    file: Arc<Mutex<SpillFile>>,
+    /// Records written but not read back yet, as of the last operation.
+    pending: Arc<AtomicUsize>,
+}
+
+impl Spill {
+    pub async fn open(path: &Path) -> std::io::Result<Self> {
+        let path = path.to_path_buf();
+        let file = tokio::task::spawn_blocking(move || SpillFile::open(&path))
+            .await
+            .map_err(std::io::Error::other)??;
+        Ok(Self {
+            pending: Arc::new(AtomicUsize::new(file.pending)),
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(Relaxed)
+    }
+
+    pub async fn push(&self, record: LogRecord) -> std::io::Result<()> {
+        self.run(move |file| file.push(&record)).await
+    }
+
+    /// The oldest record, if any, or an error for a record that can't be read.
+    pub async fn pop(&self) -> std::io::Result<Option<LogRecord>> {
+        self.run(SpillFile::pop).await
+    }
+
+    /// Writes the records before the ones not read back yet, e.g. the records
+    /// of a queue in memory that are older than the spilled ones, and unlocks
+    /// the file for the next one to open it.
+    pub async fn release(self, records: Vec<LogRecord>) -> std::io::Result<()> {
+        self.run(move |file| file.release(&records)).await
+    }
+
+    async fn run<R: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&mut SpillFile) -> std::io::Result<R> + Send + 'static,
+    ) -> std::io::Result<R> {
+        let (file, pending) = (self.file.clone(), self.pending.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut file = file.lock().unwrap();
+            let result = operation(&mut file);
+            pending.store(file.pending, Relaxed);
+            result
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+}
+
+struct SpillFile {
+    path: PathBuf,
+    writer: File,
+    reader: BufReader<File>,
+    /// Records written but not read back yet.
+    pending: usize,
+}
+
+impl SpillFile {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut writer = OpenOptions::new().create(true).append(true).open(path)?;
+        if let Err(TryLockError::WouldBlock) = writer.try_lock() {
+            return Err(std::io::Error::other(format!(
+                "{} is used by another process, e.g. logup with the same --spill-dir",
+                path.display()
+            )));
+        }
+        let mut reader = BufReader::new(File::open(path)?);
+        let (mut pending, mut last) = (0, b'\n');
+        loop {
+            let buf = reader.fill_buf()?;
+            let Some(&end) = buf.last() else {
+                break;
+            };
+            pending += buf.iter().filter(|&&b| b == b'\n').count();
+            last = end;
+            let len = buf.len();
+            reader.consume(len);
+        }
+        if last != b'\n' {
+            // terminate a record cut short by a crash, it will fail to decode
+            writer.write_all(b"\n")?;
+            pending += 1;
+        }
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer,
+            reader,
+            pending,
+        })
+    }
+
+    fn push(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let mut line = encode(record).to_string().into_bytes();
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> std::io::Result<Option<LogRecord>> {
+        if self.pending == 0 {
+            return Ok(None);
+        }
+        let mut line = Vec::new();
+        // the record is lost even if it can't be read, so that the next one is tried
+        let read = self.reader.read_until(b'\n', &mut line);
+        self.pending -= 1;
+        if self.pending == 0 {
+            // start over once everything was read back so the file doesn't grow
+            self.writer.set_len(0)?;
+            self.reader.seek(SeekFrom::Start(0))?;
+        }
+        read?;
+        decode(&line)
+            .map(Some)
+            .ok_or_else(|| std::io::Error::other("Invalid record in the spill file"))
+    }
+
+    fn release(&mut self, records: &[LogRecord]) -> std::io::Result<()> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".tmp");
+        let mut rewrite = || {
+            let mut file = BufWriter::new(File::create(&path)?);
+            for record in records {
+                serde_json::to_writer(&mut file, &encode(record))?;
+                file.write_all(b"\n")?;
+            }
+            std::io::copy(&mut self.reader, &mut file)?;
+            file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            std::fs::rename(&path, &self.path)
+        };
+        let result = rewrite();
+        if result.is_err() {
+            // the file is left as it was
+            let _ = std::fs::remove_file(&path);
+            let _ = self.writer.unlock();
+        }
+        result
+    }
+}
+
+fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+fn encode(record: &LogRecord) -> Value {
+    let mut json = json!({
+        "timestamp": nanos(record.timestamp),
+        "observed_timestamp": nanos(record.observed_timestamp),
+        "attributes": record.attributes,
+        "source": record.source,
+    });
+    match std::str::from_utf8(&record.message) {
+        Ok(message) => json["message"] = message.into(),
+        Err(_) => json["message_bytes"] = record.message.clone().into(),
+    }
+    if let Some(severity) = record.severity {
+        json["severity"] = severity.as_str().into();
+    }
+    json
+}
+
+fn decode(line: &[u8]) -> Option<LogRecord> {
+    let json: Map<String, Value> = serde_json::from_slice(line).ok()?;
+    let time = |key| Some(UNIX_EPOCH + Duration::from_nanos(json.get(key)?.as_u64()?));
+    let message = match json.get("message") {
+        Some(message) => message.as_str()?.as_bytes().to_vec(),
+        None => serde_json::from_value(json.get("message_bytes")?.clone()).ok()?,
+    };
+    let mut record = LogRecord::new(time("timestamp")?, message);
+    record.observed_timestamp = time("observed_timestamp")?;
+    if let Some(severity) = json.get("severity") {
+        record.severity = Some(Severity::parse(severity.as_str()?)?);
+    }
+    record.attributes = serde_json::from_value(json.get("attributes")?.clone()).ok()?;
+    record.source = json.get("source")?.as_str()?.to_string();
+    Some(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spill_and_reopen() {
+        let path = std::env::temp_dir()
+            .join(format!("logup-spill-{}", std::process::id()))
+            .join("sink.spill");
+        let _ = std::fs::remove_file(&path);
+
+        let mut json = LogRecord::new(UNIX_EPOCH + Duration::from_nanos(42), b"{}\n".to_vec());
+        json.severity = Some(Severity::Warn);
+        json.attributes.insert("user".to_string(), json!({"id": 1}));
+        json.source = "stdin".to_string();
+        let binary = LogRecord::new(SystemTime::now(), b"\xff\n".to_vec());
+
+        let spill = Spill::open(&path).await.unwrap();
+        spill.push(json.clone()).await.unwrap();
+        spill.push(binary.clone()).await.unwrap();
+        assert_eq!(spill.pop().await.unwrap(), Some(json.clone()));
+        drop(spill);
+
+        // the records read back are still in the file until all are
+        let spill = Spill::open(&path).await.unwrap();
+        assert_eq!(spill.pending(), 2);
+        assert_eq!(spill.pop().await.unwrap(), Some(json.clone()));
+        assert_eq!(spill.pop().await.unwrap(), Some(binary.clone()));
+        assert_eq!(spill.pop().await.unwrap(), None);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        spill.push(binary.clone()).await.unwrap();
+        assert_eq!(spill.pop().await.unwrap(), Some(binary.clone()));
+
+        // the records of memory go first
+        spill.push(binary.clone()).await.unwrap();
+        spill.push(json.clone()).await.unwrap();
+        assert_eq!(spill.pop().await.unwrap(), Some(binary.clone()));
+        spill
+            .release(vec![json.clone(), binary.clone()])
+            .await
+            .unwrap();
+        let spill = Spill::open(&path).await.unwrap();
+        // a single process spills to the file at a time
+        assert!(Spill::open(&path).await.is_err());
+        assert_eq!(spill.pending(), 3);
+        assert_eq!(spill.pop().await.unwrap(), Some(json.clone()));
+        assert_eq!(spill.pop().await.unwrap(), Some(binary.clone()));
+        assert_eq!(spill.pop().await.unwrap(), Some(json));
+        drop(spill);
+
+        std::fs::write(&path, b"{\"timestamp\"").unwrap();
+        let spill = Spill::open(&path).await.unwrap();
+        assert_eq!(spill.pending(), 1);
+        assert!(spill.pop().await.is_err());
+        assert_eq!(spill.pending(), 0);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
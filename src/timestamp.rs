@@ -0,0 +1,369 @@
+use crate::record::LogRecord;
+use crate::writer::AsyncLogWriter;
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDateTime, TimeZone, Utc};
+use clap::Args;
+use regex::Regex;
+use serde_json::Value;
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime};
+
+pub static RFC3339: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d+)?(?:Z|[+-]\d{2}:?\d{2})?")
+        .unwrap()
+});
+pub static SYSLOG: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\b(?:Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec) [ \d]\d \d{2}:\d{2}:\d{2}\b")
+        .unwrap()
+});
+pub static HTTPDATE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\d{2}/(?:Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec)/\d{4}:\d{2}:\d{2}:\d{2} [+-]\d{4}")
+        .unwrap()
+});
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimestampFormat {
+    /// Also accepts a space instead of `T`, a comma before the fraction and
+    /// no offset, e.g. `2024-02-29 12:34:56,789`.
+    Rfc3339,
+    /// `Feb 29 12:34:56`, without a year.
+    Syslog,
+    /// `29/Feb/2024:12:34:56 +0000` as in nginx and Apache access logs.
+    HttpDate,
+    /// A strftime pattern.
+    Strftime(String),
+}
+
+impl TimestampFormat {
+    fn parse(format: &str) -> Result<Self, String> {
+        match format {
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            "syslog" => Ok(TimestampFormat::Syslog),
+            "httpdate" => Ok(TimestampFormat::HttpDate),
+            _ if format.contains('%') => Ok(TimestampFormat::Strftime(format.to_string())),
+            _ => Err("expected rfc3339, syslog, httpdate or a strftime pattern".to_string()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Timezone {
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl Timezone {
+    fn parse(timezone: &str) -> Result<Self, String> {
+        match timezone {
+            "local" => Ok(Timezone::Local),
+            "UTC" | "utc" | "Z" => Ok(Timezone::Fixed(FixedOffset::east_opt(0).unwrap())),
+            _ => timezone
+                .parse()
+                .map(Timezone::Fixed)
+                .map_err(|_| "expected local, UTC or an offset like +02:00".to_string()),
+        }
+    }
+
+    fn resolve(&self, time: &NaiveDateTime) -> Option<SystemTime> {
+        match self {
+            Timezone::Local => Local.from_local_datetime(time).earliest().map(Into::into),
+            Timezone::Fixed(offset) => offset.from_local_datetime(time).earliest().map(Into::into),
+        }
+    }
+
+    fn year(&self, now: SystemTime) -> i32 {
+        match self {
+            Timezone::Local => DateTime::<Local>::from(now).year(),
+            Timezone::Fixed(offset) => DateTime::<Utc>::from(now).with_timezone(offset).year(),
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct TimestampArgs {
+    #[arg(
+        long,
+        help = "Use the time found in each log as its timestamp rather than the time it was read"
+    )]
+    timestamp_extract: bool,
+
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        value_parser = TimestampFormat::parse,
+        default_values = ["rfc3339", "syslog", "httpdate"],
+        requires = "timestamp_extract",
+        help = "rfc3339, syslog, httpdate or a strftime pattern matching the start of the log or of its first [...] field, the first one found wins"
+    )]
+    timestamp_format: Vec<TimestampFormat>,
+
+    #[arg(
+        long,
+        value_name = "KEY",
+        requires = "timestamp_extract",
+        help = "Take the time from a parsed field rather than from the log"
+    )]
+    timestamp_field: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "TIMEZONE",
+        value_parser = Timezone::parse,
+        default_value = "local",
+        requires = "timestamp_extract",
+        help = "Timezone of the times without an offset: local, UTC or an offset like +02:00"
+    )]
+    timestamp_timezone: Timezone,
+}
+
+pub struct TimestampExtractor {
+    formats: Vec<TimestampFormat>,
+    field: Option<String>,
+    timezone: Timezone,
+}
+
+impl TimestampExtractor {
+    pub fn new(args: &TimestampArgs) -> Option<Self> {
+        if !args.timestamp_extract {
+            return None;
+        }
+        Some(Self {
+            formats: args.timestamp_format.clone(),
+            field: args.timestamp_field.clone(),
+            timezone: args.timestamp_timezone,
+        })
+    }
+
+    /// Sets the timestamp of the record unless a parser already did, keeping
+    /// the read time if none is found.
+    pub fn extract(&self, record: &mut LogRecord, now: SystemTime) {
+        if record.timestamp != record.observed_timestamp {
+            return;
+        }
+        match &self.field {
+            Some(field) => {
+                if let Some(Value::String(value)) = record.attributes.get(field) {
+                    if let Some(timestamp) = self.find(value, now) {
+                        record.timestamp = timestamp;
+                        record.attributes.remove(field);
+                    }
+                }
+            }
+            None => {
+                if let Some(timestamp) = self.find(&String::from_utf8_lossy(&record.message), now) {
+                    record.timestamp = timestamp;
+                }
+            }
+        }
+    }
+
+    fn find(&self, text: &str, now: SystemTime) -> Option<SystemTime> {
+        self.formats
+            .iter()
+            .find_map(|format| self.find_format(format, text, now))
+    }
+
+    fn find_format(
+        &self,
+        format: &TimestampFormat,
+        text: &str,
+        now: SystemTime,
+    ) -> Option<SystemTime> {
+        match format {
+            TimestampFormat::Rfc3339 => {
+                let found = find_anchored(&RFC3339, text)?.replace(',', ".");
+                if let Ok(time) = DateTime::parse_from_str(
+                    &found.replacen(' ', "T", 1).replace('Z', "+00:00"),
+                    "%Y-%m-%dT%H:%M:%S%.f%z",
+                ) {
+                    return Some(time.into());
+                }
+                let time = NaiveDateTime::parse_from_str(&found, "%Y-%m-%dT%H:%M:%S%.f")
+                    .or_else(|_| NaiveDateTime::parse_from_str(&found, "%Y-%m-%d %H:%M:%S%.f"))
+                    .ok()?;
+                self.timezone.resolve(&time)
+            }
+            TimestampFormat::Syslog => {
+                let found = find_anchored(&SYSLOG, text)?;
+                let year = self.timezone.year(now);
+                let time = NaiveDateTime::parse_from_str(
+                    &format!("{} {}", year, found),
+                    "%Y %b %e %H:%M:%S",
+                )
+                .ok()?;
+                let timestamp = self.timezone.resolve(&time)?;
+                // logs from December read in January
+                if timestamp > now + Duration::from_secs(86400) {
+                    let time = time.with_year(year - 1)?;
+                    return self.timezone.resolve(&time);
+                }
+                Some(timestamp)
+            }
+            TimestampFormat::HttpDate => {
+                let found = find_anchored(&HTTPDATE, text)?;
+                DateTime::parse_from_str(found, "%d/%b/%Y:%H:%M:%S %z")
+                    .ok()
+                    .map(Into::into)
+            }
+            TimestampFormat::Strftime(pattern) => anchors(text).find_map(|text| {
+                if let Ok((time, _)) = DateTime::parse_and_remainder(text, pattern) {
+                    return Some(time.into());
+                }
+                let (time, _) = NaiveDateTime::parse_and_remainder(text, pattern).ok()?;
+                self.timezone.resolve(&time)
+            }),
+        }
+    }
+}
+
+/// Where a time is looked for, so that the dates in the message are left
+/// alone: the start of the log and the start of its first bracketed field,
+/// as in `1.2.3.4 - - [29/Feb/2024:12:34:56 +0000] "GET /"`.
+fn anchors(text: &str) -> impl Iterator<Item = &str> {
+    let start = text.trim_start_matches(|c: char| c == '[' || c.is_whitespace());
+    let bracketed = text.split_once('[').map(|(_, rest)| rest);
+    std::iter::once(start).chain(bracketed)
+}
+
+fn find_anchored<'a>(regex: &Regex, text: &'a str) -> Option<&'a str> {
+    anchors(text).find_map(|text| {
+        regex
+            .find(text)
+            .filter(|found| found.start() == 0)
+            .map(|found| found.as_str())
+    })
+}
+
+/// Sets the timestamp of each record from the time found in its content.
+pub struct TimestampWriter<T: AsyncLogWriter> {
+    inner: T,
+    extractor: TimestampExtractor,
+}
+
+impl<T: AsyncLogWriter> TimestampWriter<T> {
+    pub fn new(inner: T, extractor: TimestampExtractor) -> Self {
+        Self { inner, extractor }
+    }
+}
+
+#[async_trait]
+impl<T: AsyncLogWriter + Send> AsyncLogWriter for TimestampWriter<T> {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let mut record = record.clone();
+        self.extractor.extract(&mut record, SystemTime::now());
+        self.inner.write_logs(&record).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::UNIX_EPOCH;
+
+    // 2024-02-29T12:34:56Z
+    const TIME: u64 = 1709210096;
+
+    fn extractor(formats: &[&str], field: Option<&str>) -> TimestampExtractor {
+        TimestampExtractor {
+            formats: formats
+                .iter()
+                .map(|f| TimestampFormat::parse(f).unwrap())
+                .collect(),
+            field: field.map(str::to_string),
+            timezone: Timezone::parse("+01:00").unwrap(),
+        }
+    }
+
+    fn extract(extractor: &TimestampExtractor, line: &str, now: SystemTime) -> SystemTime {
+        let mut record = LogRecord::new(now, line.as_bytes().to_vec());
+        extractor.extract(&mut record, now);
+        record.timestamp
+    }
+
+    #[test]
+    fn extract_formats() {
+        let extractor = extractor(
+            &["rfc3339", "syslog", "httpdate", "%d.%m.%Y %H:%M:%S"],
+            None,
+        );
+        let now = SystemTime::now();
+        let time = UNIX_EPOCH + Duration::from_secs(TIME);
+        let millis = time + Duration::from_millis(789);
+
+        assert_eq!(
+            extract(&extractor, "2024-02-29T12:34:56.789Z INFO a\n", now),
+            millis
+        );
+        assert_eq!(
+            extract(&extractor, "[2024-02-29T14:34:56+02:00] a\n", now),
+            time
+        );
+        // without an offset the timezone applies
+        assert_eq!(
+            extract(&extractor, "2024-02-29 13:34:56,789 INFO a\n", now),
+            millis
+        );
+        assert_eq!(
+            extract(
+                &extractor,
+                "1.2.3.4 - - [29/Feb/2024:12:34:56 +0000] \"GET /\"\n",
+                now
+            ),
+            time
+        );
+        assert_eq!(extract(&extractor, "29.02.2024 13:34:56 a\n", now), time);
+        assert_eq!(extract(&extractor, "no time here\n", now), now);
+        // times in the message are not the time of the log
+        assert_eq!(
+            extract(&extractor, "INFO renewed until 2025-02-28T00:00:00Z\n", now),
+            now
+        );
+        assert_eq!(
+            extract(&extractor, "job [nightly] last run Feb 28 01:00:00\n", now),
+            now
+        );
+    }
+
+    #[test]
+    fn extract_syslog_year() {
+        let extractor = extractor(&["syslog"], None);
+        let now = UNIX_EPOCH + Duration::from_secs(TIME);
+        assert_eq!(
+            extract(&extractor, "Feb 29 13:34:56 host app[1]: a\n", now),
+            now
+        );
+        // 2023-12-31T22:59:59Z, in the past year
+        assert_eq!(
+            extract(&extractor, "Dec 31 23:59:59 host app[1]: a\n", now),
+            UNIX_EPOCH + Duration::from_secs(1704063599)
+        );
+    }
+
+    #[test]
+    fn extract_from_field() {
+        let extractor = extractor(&["%d/%m/%Y %H:%M:%S %z"], Some("when"));
+        let now = SystemTime::now();
+        let mut record = LogRecord::new(now, b"2024-02-29T12:34:56Z ignored\n".to_vec());
+        record
+            .attributes
+            .insert("when".to_string(), json!("29/02/2024 12:34:56 +0000"));
+        extractor.extract(&mut record, now);
+        assert_eq!(record.timestamp, UNIX_EPOCH + Duration::from_secs(TIME));
+        assert!(record.attributes.is_empty());
+
+        // parsers lifting the timestamp win
+        let mut record = LogRecord::new(now, b"2024-02-29T12:34:56Z\n".to_vec());
+        record.timestamp = UNIX_EPOCH;
+        extractor.extract(&mut record, now);
+        assert_eq!(record.timestamp, UNIX_EPOCH);
+    }
+}
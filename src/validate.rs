@@ -0,0 +1,141 @@
+use crate::dedup::Deduplicator;
+use crate::enrich::Enricher;
+use crate::filter::Filter;
+use crate::limit::Limiter;
+use crate::parser::Parser as LineParser;
+use crate::redact::Redactor;
+use crate::severity::SeverityDetector;
+use crate::timestamp::TimestampExtractor;
+use crate::writer_multiline::MultilineRules;
+use crate::writer_queue::Overflow;
+use crate::{
+    describe_sinks, writer_aws, writer_firehose, writer_journald, writer_kinesis, writer_newrelic,
+    LogupArgs,
+};
+use std::time::Instant;
+
+/// Prints the pipeline and checks each sink without sending any log,
+/// returning whether all the checks passed.
+pub async fn validate(args: &LogupArgs) -> bool {
+    let input = args
+        .input_file
+        .as_ref()
+        .map_or("stdin".to_string(), |path| path.display().to_string());
+    println!("Input: {}", input);
+
+    // in processing order
+    let stages = [
+        ("strip-ansi", args.strip_ansi),
+        ("multiline", MultilineRules::new(&args.multiline).is_some()),
+        ("parse", LineParser::new(&args.parse).is_some()),
+        ("enrich", Enricher::new(&args.enrich).await.is_some()),
+        ("severity", SeverityDetector::new(&args.severity).is_some()),
+        (
+            "timestamp",
+            TimestampExtractor::new(&args.timestamp).is_some(),
+        ),
+        ("redact", Redactor::new(&args.redact).is_some()),
+        (
+            "dedup",
+            Deduplicator::new(&args.dedup, Instant::now()).is_some(),
+        ),
+        ("filter", Filter::new(&args.filter.filter, None).is_some()),
+        ("route", !args.route.route.is_empty()),
+    ];
+    let stages: Vec<&str> = stages
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(stage, _)| *stage)
+        .collect();
+    println!(
+        "Stages: {}",
+        if stages.is_empty() {
+            "none".to_string()
+        } else {
+            stages.join(", ")
+        }
+    );
+
+    println!("Sinks:");
+    let mut ok = true;
+    let mut names: Vec<String> = vec![];
+    for (name, sink_args) in args.all_sinks() {
+        for (kind, name, description) in describe_sinks(sink_args, name) {
+            if names.contains(&name) {
+                println!("  {}: {}\n    FAILED: defined twice", name, description);
+                ok = false;
+                continue;
+            }
+            let rules = [
+                (
+                    "filter",
+                    Filter::new(&args.filter.filter, Some(&name)).is_some(),
+                ),
+                (
+                    "limit",
+                    Limiter::new(&args.limit, &name, Instant::now()).is_some(),
+                ),
+                ("route", args.route.route.iter().any(|r| r.sink() == name)),
+                (
+                    "overflow",
+                    args.queue.overflow(&name) != Overflow::DropNewest,
+                ),
+                (
+                    "max-memory-bytes",
+                    args.queue.max_memory_bytes(Some(&name)).is_some(),
+                ),
+            ];
+            let rules: Vec<&str> = rules
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(rule, _)| *rule)
+                .collect();
+            if rules.is_empty() {
+                println!("  {}: {}", name, description);
+            } else {
+                println!("  {}: {} ({})", name, description, rules.join(", "));
+            }
+
+            let checked = match kind {
+                "aws" => writer_aws::validate(&sink_args.aws).await,
+                "kinesis" => writer_kinesis::validate(&sink_args.kinesis).await,
+                "firehose" => writer_firehose::validate(&sink_args.firehose).await,
+                "newrelic" => writer_newrelic::validate(&sink_args.newrelic).await,
+                "journald" => writer_journald::validate(&sink_args.journald),
+                _ => unreachable!(),
+            };
+            match checked {
+                Ok(message) => println!("    OK: {}", message),
+                Err(message) => {
+                    println!("    FAILED: {}", message);
+                    ok = false;
+                }
+            }
+            names.push(name);
+        }
+    }
+    if names.is_empty() {
+        println!("  none, the logs only go to stdout");
+    }
+
+    let rule_sinks = args.filter.filter.iter().filter_map(|rule| rule.sink());
+    let rule_sinks = rule_sinks.chain(args.limit.limit.iter().filter_map(|rule| rule.sink()));
+    let rule_sinks = rule_sinks.chain(args.queue.overflow.iter().filter_map(|rule| rule.sink()));
+    let rule_sinks = rule_sinks.chain(
+        args.queue
+            .max_memory_bytes
+            .iter()
+            .filter_map(|rule| rule.sink()),
+    );
+    let rule_sinks = rule_sinks.chain(args.route.route.iter().map(|route| route.sink()));
+    let mut unknown: Vec<&str> = rule_sinks
+        .filter(|sink| !names.iter().any(|n| n == sink))
+        .collect();
+    unknown.sort();
+    unknown.dedup();
+    for sink in unknown {
+        println!("Rule for {} which is not enabled", sink);
+        ok = false;
+    }
+    ok
+}
@@ -1,5 +1,5 @@
+use crate::record::LogRecord;
 use async_trait::async_trait;
-use std::time::SystemTime;
 use tokio::io::AsyncWriteExt;
 
 #[cfg(test)]
@@ -8,19 +8,41 @@ use mockall::{automock, predicate::*};
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait AsyncLogWriter {
-    async fn write_logs(&mut self, time: SystemTime, buf: &[u8]) -> std::io::Result<()>;
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()>;
+
+    /// Delivers anything that has been buffered so far.
+    async fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Flushes and releases the writer, no more logs are written afterwards.
+    async fn close(&mut self) -> std::io::Result<()> {
+        self.flush().await
+    }
 }
 
 #[async_trait]
 impl AsyncLogWriter for tokio::io::Stdout {
-    async fn write_logs(&mut self, _time: SystemTime, buf: &[u8]) -> std::io::Result<()> {
-        self.write_all(buf).await
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        self.write_all(&record.message).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        AsyncWriteExt::flush(self).await
     }
 }
 
 #[async_trait]
 impl<T: AsyncLogWriter + Send + ?Sized> AsyncLogWriter for Box<T> {
-    async fn write_logs(&mut self, time: SystemTime, buf: &[u8]) -> std::io::Result<()> {
-        (**self).write_logs(time, buf).await
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        (**self).write_logs(record).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        (**self).flush().await
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        (**self).close().await
     }
 }
@@ -0,0 +1,73 @@
+use crate::record::LogRecord;
+use crate::writer::AsyncLogWriter;
+use async_trait::async_trait;
+use regex::bytes::Regex;
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+/// CSI sequences such as colors, OSC sequences such as titles and links, other
+/// escape sequences, and control characters besides tabs and newlines.
+static CONTROL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(
+        r"\x1b\[[0-?]*[ -/]*[@-~]",
+        r"|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)",
+        r"|\x1b[ -/]*[0-~]",
+        r"|[\x00-\x08\x0b-\x1f\x7f]",
+    ))
+    .unwrap()
+});
+
+pub fn strip_ansi(message: &[u8]) -> Cow<'_, [u8]> {
+    CONTROL.replace_all(message, &b""[..])
+}
+
+/// Strips terminal escape sequences, e.g. colors, from the messages.
+pub struct AnsiStripWriter<T: AsyncLogWriter> {
+    inner: T,
+}
+
+impl<T: AsyncLogWriter> AnsiStripWriter<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl<T: AsyncLogWriter + Send> AsyncLogWriter for AnsiStripWriter<T> {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        match strip_ansi(&record.message) {
+            Cow::Borrowed(_) => self.inner.write_logs(record).await,
+            Cow::Owned(message) => self.inner.write_logs(&record.with_message(message)).await,
+        }
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_sequences() {
+        assert_eq!(
+            strip_ansi(b"\x1b[1;31mERROR\x1b[0m failed\r\n"),
+            &b"ERROR failed\n"[..]
+        );
+        assert_eq!(
+            strip_ansi(b"\x1b]8;;http://x\x1b\\link\x1b]8;;\x07 \x1b(Bok\x07\x08\n"),
+            &b"link ok\n"[..]
+        );
+        assert_eq!(
+            strip_ansi("tab\tütf8\n".as_bytes()),
+            "tab\tütf8\n".as_bytes()
+        );
+        assert!(matches!(strip_ansi(b"plain\n"), Cow::Borrowed(_)));
+    }
+}
@@ -1,14 +1,18 @@
-use crate::report_err;
+use crate::diag::error_chain;
+use crate::log_error;
+use crate::metrics::{metrics, SinkMetrics};
+use crate::record::LogRecord;
 use crate::writer::AsyncLogWriter;
 use async_trait::async_trait;
-use aws_config::BehaviorVersion;
+use aws_config::{BehaviorVersion, Region, SdkConfig};
 use aws_sdk_cloudwatchlogs::config::retry::RetryConfig;
+use aws_sdk_cloudwatchlogs::config::ProvideCredentials;
 use aws_sdk_cloudwatchlogs::types::InputLogEvent;
 use aws_sdk_cloudwatchlogs::Client;
 use clap::Args;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
 
-#[derive(Args)]
+#[derive(Args, Clone, PartialEq)]
 #[group()]
 pub struct AWSArgs {
     #[arg(
@@ -34,16 +38,24 @@ pub struct AWSArgs {
         help = "Log stream name [default: hostname]"
     )]
     aws_log_stream_name: Option<String>,
+
+    #[arg(
+        long,
+        requires = "aws",
+        help = "AWS region [default: from the environment]"
+    )]
+    aws_region: Option<String>,
 }
 
 pub struct AWSLogsWriter {
     client: Client,
     log_group_name: String,
     log_stream_name: String,
+    metrics: Arc<SinkMetrics>,
 }
 
 impl AWSLogsWriter {
-    pub async fn new(args: &AWSArgs, max_retries: u32) -> Option<Self> {
+    pub async fn new(args: &AWSArgs, max_retries: u32, name: &str) -> Option<Self> {
         if !args.aws {
             return None;
         }
@@ -54,12 +66,7 @@ impl AWSLogsWriter {
             .clone()
             .unwrap_or_else(|| hostname::get().unwrap().into_string().unwrap());
         let client = aws_sdk_cloudwatchlogs::Client::new(
-            &aws_config::defaults(BehaviorVersion::latest())
-                .retry_config(
-                    RetryConfig::standard().with_max_attempts(max_retries + 1), // initial call is included
-                )
-                .load()
-                .await,
+            &load_aws_config(max_retries, args.aws_region.clone()).await,
         );
         create_log_group(&client, &log_group_name).await;
         create_log_stream(&client, &log_group_name, &log_stream_name).await;
@@ -67,28 +74,100 @@ impl AWSLogsWriter {
             client,
             log_group_name,
             log_stream_name,
+            metrics: metrics().sink(name),
         };
         Some(writer)
     }
 }
 
+/// Where the logs are sent, if enabled.
+pub fn describe(args: &AWSArgs) -> Option<String> {
+    if !args.aws {
+        return None;
+    }
+    let mut description = format!(
+        "CloudWatch Logs group {}, stream {}",
+        args.aws_log_group_name.as_deref()?,
+        args.aws_log_stream_name.as_deref().unwrap_or("[hostname]")
+    );
+    if let Some(region) = &args.aws_region {
+        description.push_str(&format!(", region {}", region));
+    }
+    Some(description)
+}
+
+/// Checks the credentials and the permission to describe the log group.
+pub async fn validate(args: &AWSArgs) -> Result<String, String> {
+    // no retries, to fail fast
+    let config = load_aws_config(0, args.aws_region.clone()).await;
+    let region = check_credentials(&config).await?;
+    let log_group_name = args.aws_log_group_name.as_deref().unwrap_or_default();
+    let groups = Client::new(&config)
+        .describe_log_groups()
+        .log_group_name_prefix(log_group_name)
+        .send()
+        .await
+        .map_err(|e| format!("Error describing log group: {}", error_chain(&e)))?;
+    let exists = groups
+        .log_groups()
+        .iter()
+        .any(|group| group.log_group_name() == Some(log_group_name));
+    Ok(format!(
+        "credentials found in {}, log group {}",
+        region,
+        if exists { "exists" } else { "will be created" }
+    ))
+}
+
+/// Resolves the credentials, returning the region.
+pub async fn check_credentials(config: &SdkConfig) -> Result<String, String> {
+    let region = config
+        .region()
+        .ok_or("no AWS region configured")?
+        .to_string();
+    config
+        .credentials_provider()
+        .ok_or("no AWS credentials configured")?
+        .provide_credentials()
+        .await
+        .map_err(|e| format!("Error resolving AWS credentials: {}", error_chain(&e)))?;
+    Ok(region)
+}
+
+/// Loads region and credentials from the environment, shared by all the AWS writers.
+pub async fn load_aws_config(max_retries: u32, region: Option<String>) -> SdkConfig {
+    let mut loader = aws_config::defaults(BehaviorVersion::latest()).retry_config(
+        RetryConfig::standard().with_max_attempts(max_retries + 1), // initial call is included
+    );
+    if let Some(region) = region {
+        loader = loader.region(Region::new(region));
+    }
+    loader.load().await
+}
+
 #[async_trait]
 impl AsyncLogWriter for AWSLogsWriter {
-    async fn write_logs(&mut self, time: SystemTime, buf: &[u8]) -> std::io::Result<()> {
-        let timestamp = time.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        // CloudWatch has no attributes, but Logs Insights discovers the fields of JSON messages
+        let message = if record.is_plain() {
+            String::from_utf8_lossy(&record.message).to_string()
+        } else {
+            record.to_json().to_string()
+        };
 
+        self.metrics.batches_sent.inc();
         self.client
             .put_log_events()
             .log_group_name(&self.log_group_name)
             .log_stream_name(&self.log_stream_name)
             .set_log_events(Some(vec![InputLogEvent::builder()
-                .timestamp(timestamp)
-                .message(String::from_utf8_lossy(buf).to_string())
+                .timestamp(record.timestamp_millis())
+                .message(message)
                 .build()
                 .unwrap()]))
             .send()
             .await
-            .unwrap();
+            .map_err(std::io::Error::other)?;
 
         Ok(())
     }
@@ -112,10 +191,12 @@ async fn create_log_stream(client: &Client, log_group_name: &str, log_stream_nam
 
     if let Err(e) = resp {
         if !is_resource_already_exists_exception!(e) {
-            report_err(&e);
-            panic!(
+            log_error!(
+                sink: log_group_name,
                 "Error creating log stream {}:log-stream:{}: {}",
-                log_group_name, log_stream_name, e
+                log_group_name,
+                log_stream_name,
+                error_chain(&e)
             );
         }
     }
@@ -130,8 +211,12 @@ async fn create_log_group(client: &Client, log_group_name: &str) {
 
     if let Err(e) = resp {
         if !is_resource_already_exists_exception!(e) {
-            report_err(&e);
-            panic!("Error creating log group: {}", e);
+            log_error!(
+                sink: log_group_name,
+                "Error creating log group {}: {}",
+                log_group_name,
+                error_chain(&e)
+            );
         }
     }
 }
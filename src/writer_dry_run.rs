@@ -0,0 +1,62 @@
+use crate::record::LogRecord;
+use crate::writer::AsyncLogWriter;
+use async_trait::async_trait;
+use tokio::io::{AsyncWrite, AsyncWriteExt, Stderr};
+
+/// Prints what a sink would send, prefixed by the sink name, in place of it.
+pub struct DryRunWriter<W: AsyncWrite + Unpin + Send = Stderr> {
+    output: W,
+    prefix: Vec<u8>,
+}
+
+impl DryRunWriter {
+    pub fn new(name: &str) -> Self {
+        Self::with_output(tokio::io::stderr(), name)
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> DryRunWriter<W> {
+    fn with_output(output: W, name: &str) -> Self {
+        Self {
+            output,
+            prefix: format!("[{}] ", name).into_bytes(),
+        }
+    }
+}
+
+#[async_trait]
+impl<W: AsyncWrite + Unpin + Send> AsyncLogWriter for DryRunWriter<W> {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let mut line = self.prefix.clone();
+        line.extend_from_slice(&record.to_bytes());
+        if !line.ends_with(b"\n") {
+            line.push(b'\n');
+        }
+        self.output.write_all(&line).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Severity;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[tokio::test]
+    async fn print_records() {
+        let mut writer = DryRunWriter::with_output(Vec::new(), "audit");
+        let mut record = LogRecord::new(UNIX_EPOCH + Duration::from_millis(1), b"plain\n".to_vec());
+        writer.write_logs(&record).await.unwrap();
+        record.message = b"partial".to_vec();
+        record.severity = Some(Severity::Warn);
+        writer.write_logs(&record).await.unwrap();
+        assert_eq!(
+            String::from_utf8(writer.output).unwrap(),
+            "[audit] plain\n[audit] {\"level\":\"warn\",\"message\":\"partial\",\"timestamp\":1}\n"
+        );
+    }
+}
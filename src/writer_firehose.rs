@@ -0,0 +1,269 @@
+use crate::batch::{backoff, Batch, MAX_BATCH_DELAY};
+use crate::diag::error_chain;
+use crate::log_error;
+use crate::metrics::{metrics, SinkMetrics};
+use crate::record::LogRecord;
+use crate::writer::AsyncLogWriter;
+use crate::writer_aws::{check_credentials, load_aws_config};
+use async_trait::async_trait;
+use aws_sdk_firehose::primitives::Blob;
+use aws_sdk_firehose::types::Record;
+use aws_sdk_firehose::Client;
+use clap::Args;
+use std::cmp::min;
+use std::sync::Arc;
+
+// PutRecordBatch limits
+const MAX_BATCH_RECORDS: usize = 500;
+const MAX_BATCH_BYTES: usize = 4 * 1024 * 1024;
+const MAX_RECORD_BYTES: usize = 1000 * 1024;
+
+#[derive(Args, Clone, PartialEq)]
+#[group()]
+pub struct FirehoseArgs {
+    #[arg(
+        long,
+        help = "Enable uploading logs to AWS Kinesis Data Firehose",
+        requires = "firehose_delivery_stream_name"
+    )]
+    firehose: bool,
+
+    #[arg(
+        long,
+        requires = "firehose",
+        env = "FIREHOSE_DELIVERY_STREAM_NAME",
+        hide_env_values = true
+    )]
+    firehose_delivery_stream_name: Option<String>,
+
+    #[arg(
+        long,
+        requires = "firehose",
+        help = "AWS region [default: from the environment]"
+    )]
+    firehose_region: Option<String>,
+}
+
+/// Where the records are sent, if enabled.
+pub fn describe(args: &FirehoseArgs) -> Option<String> {
+    if !args.firehose {
+        return None;
+    }
+    let mut description = format!(
+        "Firehose delivery stream {}",
+        args.firehose_delivery_stream_name.as_deref()?
+    );
+    if let Some(region) = &args.firehose_region {
+        description.push_str(&format!(", region {}", region));
+    }
+    Some(description)
+}
+
+/// Checks the credentials and the permission to describe the delivery stream.
+pub async fn validate(args: &FirehoseArgs) -> Result<String, String> {
+    let config = load_aws_config(0, args.firehose_region.clone()).await;
+    let region = check_credentials(&config).await?;
+    let stream = Client::new(&config)
+        .describe_delivery_stream()
+        .delivery_stream_name(
+            args.firehose_delivery_stream_name
+                .as_deref()
+                .unwrap_or_default(),
+        )
+        .send()
+        .await
+        .map_err(|e| format!("Error describing delivery stream: {}", error_chain(&e)))?;
+    let status = stream
+        .delivery_stream_description()
+        .map_or("unknown", |d| d.delivery_stream_status().as_str());
+    Ok(format!(
+        "credentials found in {}, delivery stream is {}",
+        region, status
+    ))
+}
+
+pub struct FirehoseWriter {
+    client: Client,
+    delivery_stream_name: String,
+    max_retries: u32,
+    metrics: Arc<SinkMetrics>,
+    batch: Batch<Record>,
+}
+
+impl FirehoseWriter {
+    pub async fn new(args: &FirehoseArgs, max_retries: u32, name: &str) -> Option<Self> {
+        if !args.firehose {
+            return None;
+        }
+
+        Some(Self {
+            client: Client::new(&load_aws_config(max_retries, args.firehose_region.clone()).await),
+            delivery_stream_name: args.firehose_delivery_stream_name.clone().unwrap(),
+            max_retries,
+            metrics: metrics().sink(name),
+            batch: Batch::new(MAX_BATCH_RECORDS, MAX_BATCH_BYTES),
+        })
+    }
+
+    /// Sends the pending batch, retrying only the records that failed, and
+    /// counts the records it could not send as dropped.
+    async fn send_batch(&mut self) {
+        let mut records = self.batch.take();
+        for attempt in 0..=self.max_retries {
+            if attempt > 0 {
+                self.metrics.retries.inc();
+                tokio::time::sleep(backoff(attempt - 1)).await;
+            }
+            self.metrics.batches_sent.inc();
+            let output = self
+                .client
+                .put_record_batch()
+                .delivery_stream_name(&self.delivery_stream_name)
+                .set_records(Some(records.clone()))
+                .send()
+                .await;
+            let output = match output {
+                Ok(output) => output,
+                Err(e) => return self.drop_records(records.len(), &error_chain(&e)),
+            };
+            if output.failed_put_count() == 0 {
+                return;
+            }
+            records = records
+                .into_iter()
+                .zip(output.request_responses())
+                .filter(|(_, response)| response.error_code().is_some())
+                .map(|(record, _)| record)
+                .collect();
+        }
+        let reason = format!("still failing after {} retries", self.max_retries);
+        self.drop_records(records.len(), &reason);
+    }
+
+    fn drop_records(&self, count: usize, reason: &str) {
+        log_error!(
+            sink: self.metrics.name,
+            "Dropped {} Firehose records for {}: {}",
+            count,
+            self.metrics.name,
+            reason
+        );
+        self.metrics.errors.inc();
+        self.metrics.dropped.add(count as u64);
+    }
+}
+
+#[async_trait]
+impl AsyncLogWriter for FirehoseWriter {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let buf = record.to_bytes();
+        let data = &buf[..min(buf.len(), MAX_RECORD_BYTES)];
+
+        if !self.batch.is_empty()
+            && (!self.batch.fits(data.len()) || self.batch.is_expired(MAX_BATCH_DELAY))
+        {
+            self.send_batch().await;
+        }
+        self.batch.push(
+            Record::builder().data(Blob::new(data)).build().unwrap(),
+            data.len(),
+        );
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        if !self.batch.is_empty() {
+            self.send_batch().await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::serve_responses;
+    use aws_sdk_firehose::config::{BehaviorVersion, Credentials, Region};
+    use serde_json::Value;
+    use std::time::SystemTime;
+
+    fn writer(endpoint: &str, max_retries: u32, max_bytes: usize) -> FirehoseWriter {
+        let config = aws_sdk_firehose::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("eu-west-1"))
+            .credentials_provider(Credentials::new("key", "secret", None, None, "test"))
+            .endpoint_url(endpoint)
+            .build();
+        FirehoseWriter {
+            client: Client::from_conf(config),
+            delivery_stream_name: "logs".to_string(),
+            max_retries,
+            metrics: Arc::default(),
+            batch: Batch::new(MAX_BATCH_RECORDS, max_bytes),
+        }
+    }
+
+    async fn write(writer: &mut FirehoseWriter, message: &str) -> std::io::Result<()> {
+        let record = LogRecord::new(SystemTime::now(), message.as_bytes().to_vec());
+        writer.write_logs(&record).await
+    }
+
+    fn records(request: &Value) -> Vec<Value> {
+        request["Records"].as_array().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn retry_failed_records() {
+        let (endpoint, requests) = serve_responses(vec![
+            r#"{"FailedPutCount":1,"RequestResponses":[{"ErrorCode":"ServiceUnavailableException","ErrorMessage":"busy"},{"RecordId":"1"}]}"#,
+            r#"{"FailedPutCount":0,"RequestResponses":[{"RecordId":"2"}]}"#,
+        ])
+        .await;
+        let mut writer = writer(&endpoint, 1, 10);
+
+        // the third record exceeds the bytes of the batch, sending the first two
+        write(&mut writer, "log1\n").await.unwrap();
+        write(&mut writer, "log2\n").await.unwrap();
+        write(&mut writer, "log3\n").await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0]["DeliveryStreamName"], "logs");
+        assert_eq!(records(&requests[0]).len(), 2);
+        assert_eq!(
+            records(&requests[1]),
+            vec![records(&requests[0])[0].clone()]
+        );
+        assert_eq!(writer.metrics.retries.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn drop_records_after_max_retries() {
+        let failed = r#"{"FailedPutCount":1,"RequestResponses":[{"ErrorCode":"InternalFailure","ErrorMessage":"oops"}]}"#;
+        let (endpoint, requests) = serve_responses(vec![failed, failed]).await;
+        let mut writer = writer(&endpoint, 1, MAX_BATCH_BYTES);
+
+        write(&mut writer, "log1\n").await.unwrap();
+        writer.flush().await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert_eq!(writer.metrics.batches_sent.get(), 2);
+        assert_eq!(writer.metrics.dropped.get(), 1);
+        assert_eq!(writer.metrics.errors.get(), 1);
+        assert!(writer.batch.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drop_the_batch_of_a_failed_request() {
+        let (endpoint, requests) = serve_responses(vec!["not json"]).await;
+        let mut writer = writer(&endpoint, 1, 10);
+
+        // the third record is not the one lost
+        write(&mut writer, "log1\n").await.unwrap();
+        write(&mut writer, "log2\n").await.unwrap();
+        write(&mut writer, "log3\n").await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(writer.metrics.dropped.get(), 2);
+        assert_eq!(writer.metrics.errors.get(), 1);
+        assert!(!writer.batch.is_empty());
+    }
+}
@@ -0,0 +1,357 @@
+use crate::diag::error_chain;
+use crate::metrics::{metrics, SinkMetrics};
+use crate::record::LogRecord;
+use crate::writer::AsyncLogWriter;
+use async_trait::async_trait;
+use clap::Args;
+use std::fs::File;
+use std::io::{Error, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::Interest;
+use tokio::net::UnixDatagram;
+
+#[derive(Args, Clone, PartialEq)]
+#[group()]
+pub struct JournaldArgs {
+    #[arg(long, help = "Enable sending logs to the systemd journal")]
+    journald: bool,
+
+    #[arg(
+        long,
+        requires = "journald",
+        default_value = "logup",
+        help = "Value of the SYSLOG_IDENTIFIER field"
+    )]
+    journald_identifier: String,
+
+    #[arg(
+        long,
+        requires = "journald",
+        value_name = "KEY=VALUE",
+        value_parser = parse_field,
+        help = "Add a custom field to every journal entry"
+    )]
+    journald_field: Vec<(String, String)>,
+
+    #[arg(
+        long,
+        requires = "journald",
+        default_value = "/run/systemd/journal/socket",
+        help = "Path of the journal native protocol socket"
+    )]
+    journald_socket: PathBuf,
+}
+
+fn parse_field(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid field '{}': expected KEY=VALUE", s))?;
+    if !is_valid_field_name(key) {
+        return Err(format!(
+            "invalid field name '{}': only A-Z, 0-9 and '_' are allowed, and it must not start with '_' or a digit",
+            key
+        ));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Field names accepted by journald, see systemd.journal-fields(7).
+fn is_valid_field_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with(|c: char| c == '_' || c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Fields set by the writer itself, which attributes can't override.
+const RESERVED_FIELDS: [&str; 3] = ["MESSAGE", "PRIORITY", "SYSLOG_IDENTIFIER"];
+
+/// Attributes with a field of their own in the journal.
+const NATIVE_FIELDS: [(&str, &str); 1] = [("process.pid", "SYSLOG_PID")];
+
+/// Maps an attribute name to a journal field name, e.g. `http.status` to `HTTP_STATUS`.
+fn to_field_name(name: &str) -> Option<String> {
+    if let Some((_, field)) = NATIVE_FIELDS.iter().find(|(key, _)| *key == name) {
+        return Some(field.to_string());
+    }
+    let field: String = name
+        .chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ ('A'..='Z' | '0'..='9') => c,
+            _ => '_',
+        })
+        .collect();
+    let field = field.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+    Some(field.chars().take(64).collect()).filter(|f: &String| is_valid_field_name(f))
+}
+
+/// Where the entries are sent, if enabled.
+pub fn describe(args: &JournaldArgs) -> Option<String> {
+    if !args.journald {
+        return None;
+    }
+    Some(format!(
+        "journal socket {}, identifier {}",
+        args.journald_socket.display(),
+        args.journald_identifier
+    ))
+}
+
+/// Checks that the socket accepts connections.
+pub fn validate(args: &JournaldArgs) -> Result<String, String> {
+    let socket = UnixDatagram::unbound().map_err(|e| e.to_string())?;
+    socket
+        .connect(&args.journald_socket)
+        .map_err(|e| format!("Error connecting to journald: {}", error_chain(&e)))?;
+    Ok("socket is reachable".to_string())
+}
+
+pub struct JournaldWriter {
+    socket: UnixDatagram,
+    identifier: String,
+    fields: Vec<(String, String)>,
+    metrics: Arc<SinkMetrics>,
+}
+
+impl JournaldWriter {
+    pub fn new(args: &JournaldArgs, name: &str) -> std::io::Result<Option<Self>> {
+        if !args.journald {
+            return Ok(None);
+        }
+
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(&args.journald_socket)?;
+        Ok(Some(Self {
+            socket,
+            identifier: args.journald_identifier.clone(),
+            fields: args.journald_field.clone(),
+            metrics: metrics().sink(name),
+        }))
+    }
+
+    fn encode(&self, record: &LogRecord) -> Vec<u8> {
+        let message = record
+            .message
+            .strip_suffix(b"\n")
+            .unwrap_or(&record.message);
+        let priority = record.severity.map_or(6, |s| s.syslog_severity());
+        let mut payload = Vec::with_capacity(message.len() + 64);
+        append_field(&mut payload, "MESSAGE", message);
+        append_field(&mut payload, "PRIORITY", priority.to_string().as_bytes());
+        append_field(
+            &mut payload,
+            "SYSLOG_IDENTIFIER",
+            self.identifier.as_bytes(),
+        );
+        for (key, value) in &self.fields {
+            append_field(&mut payload, key, value.as_bytes());
+        }
+        for (key, value) in &record.attributes {
+            if let Some(field) =
+                to_field_name(key).filter(|f| !RESERVED_FIELDS.contains(&f.as_str()))
+            {
+                let value = match value {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                append_field(&mut payload, &field, value.as_bytes());
+            }
+        }
+        payload
+    }
+
+    /// Large entries don't fit in a datagram, in which case journald expects
+    /// the payload in a sealed memfd passed along with SCM_RIGHTS.
+    async fn send_memfd(&self, payload: &[u8]) -> std::io::Result<()> {
+        let memfd = create_sealed_memfd(payload)?;
+        self.socket
+            .async_io(Interest::WRITABLE, || send_fd(&self.socket, &memfd))
+            .await
+    }
+}
+
+/// Serializes a field in the journal native protocol: `KEY=value\n` when the
+/// value has no newlines, otherwise `KEY\n` followed by the little-endian
+/// 64-bit length and the raw value.
+fn append_field(payload: &mut Vec<u8>, key: &str, value: &[u8]) {
+    payload.extend_from_slice(key.as_bytes());
+    if value.contains(&b'\n') {
+        payload.push(b'\n');
+        payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        payload.push(b'=');
+    }
+    payload.extend_from_slice(value);
+    payload.push(b'\n');
+}
+
+fn create_sealed_memfd(payload: &[u8]) -> std::io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::memfd_create(
+            c"logup-journal".as_ptr(),
+            libc::MFD_ALLOW_SEALING | libc::MFD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(payload)?;
+
+    let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(file.into())
+}
+
+fn send_fd(socket: &UnixDatagram, fd: &OwnedFd) -> std::io::Result<()> {
+    let raw_fd = fd.as_raw_fd();
+    let fd_size = std::mem::size_of_val(&raw_fd);
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fd_size as u32) } as usize];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control.len() as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fd_size as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast(), raw_fd);
+    }
+
+    if unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl AsyncLogWriter for JournaldWriter {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let payload = self.encode(record);
+        self.metrics.batches_sent.inc();
+        match self.socket.send(&payload).await {
+            Ok(_) => Ok(()),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EMSGSIZE | libc::ENOBUFS)) => {
+                self.send_memfd(&payload).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Severity;
+    use serde_json::json;
+    use std::io::{Read, Seek};
+    use std::os::unix::net::UnixDatagram as StdUnixDatagram;
+    use std::time::SystemTime;
+
+    fn args(socket: PathBuf) -> JournaldArgs {
+        JournaldArgs {
+            journald: true,
+            journald_identifier: "test".to_string(),
+            journald_field: vec![("APP".to_string(), "foo".to_string())],
+            journald_socket: socket,
+        }
+    }
+
+    /// Receives a single fd passed with SCM_RIGHTS, mimicking journald.
+    fn recv_fd(socket: &StdUnixDatagram) -> File {
+        let mut control = [0u8; 64];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = control.len() as _;
+        assert!(unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) } >= 0);
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            assert_eq!((*cmsg).cmsg_type, libc::SCM_RIGHTS);
+            File::from_raw_fd(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast()))
+        }
+    }
+
+    #[test]
+    fn validate_field_names() {
+        assert!(parse_field("FOO_1=bar=baz").is_ok());
+        assert!(parse_field("_FOO=bar").is_err());
+        assert!(parse_field("1FOO=bar").is_err());
+        assert!(parse_field("foo=bar").is_err());
+        assert!(parse_field("FOO").is_err());
+
+        assert_eq!(
+            to_field_name("http.status"),
+            Some("HTTP_STATUS".to_string())
+        );
+        assert_eq!(to_field_name("_1foo"), Some("FOO".to_string()));
+        assert_eq!(to_field_name("_"), None);
+    }
+
+    #[tokio::test]
+    async fn send_entry() {
+        let dir = std::env::temp_dir().join(format!("logup-journald-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("socket");
+        let _ = std::fs::remove_file(&path);
+        let server = StdUnixDatagram::bind(&path).unwrap();
+
+        assert!(validate(&args(path.clone())).is_ok());
+        assert!(validate(&args(dir.join("missing"))).is_err());
+        let mut writer = JournaldWriter::new(&args(path), "journald")
+            .unwrap()
+            .unwrap();
+        let mut record = LogRecord::new(SystemTime::now(), b"hello\n".to_vec());
+        record.severity = Some(Severity::Error);
+        record.attributes.insert("user.id".to_string(), json!(42));
+        record
+            .attributes
+            .insert("message".to_string(), json!("other"));
+        record.attributes.insert("priority".to_string(), json!(1));
+        record
+            .attributes
+            .insert("process.pid".to_string(), json!(1234));
+        writer.write_logs(&record).await.unwrap();
+        writer
+            .write_logs(&LogRecord::new(
+                SystemTime::now(),
+                b"multi\nline\n".to_vec(),
+            ))
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 1024];
+        let size = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..size],
+            b"MESSAGE=hello\nPRIORITY=3\nSYSLOG_IDENTIFIER=test\nAPP=foo\nSYSLOG_PID=1234\nUSER_ID=42\n"
+        );
+
+        let size = server.recv(&mut buf).unwrap();
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&10u64.to_le_bytes());
+        expected.extend_from_slice(b"multi\nline\nPRIORITY=6\nSYSLOG_IDENTIFIER=test\nAPP=foo\n");
+        assert_eq!(&buf[..size], expected.as_slice());
+
+        // larger than the maximum datagram size
+        let large = vec![b'x'; 1024 * 1024];
+        writer
+            .write_logs(&LogRecord::new(SystemTime::now(), large.clone()))
+            .await
+            .unwrap();
+        let mut payload = vec![];
+        let mut memfd = recv_fd(&server);
+        memfd.rewind().unwrap(); // the file offset is shared with the sender
+        memfd.read_to_end(&mut payload).unwrap();
+        assert!(payload.starts_with(b"MESSAGE=xxx"));
+        assert_eq!(payload.len(), large.len() + 51);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+use crate::batch::{backoff, Batch, MAX_BATCH_DELAY};
+use crate::diag::error_chain;
+use crate::log_error;
+use crate::metrics::{metrics, SinkMetrics};
+use crate::record::LogRecord;
+use crate::writer::AsyncLogWriter;
+use crate::writer_aws::{check_credentials, load_aws_config};
+use async_trait::async_trait;
+use aws_sdk_kinesis::primitives::Blob;
+use aws_sdk_kinesis::types::PutRecordsRequestEntry;
+use aws_sdk_kinesis::Client;
+use clap::Args;
+use std::cmp::min;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// PutRecords limits
+const MAX_BATCH_RECORDS: usize = 500;
+const MAX_BATCH_BYTES: usize = 5 * 1024 * 1024;
+const MAX_RECORD_BYTES: usize = 1024 * 1024;
+const MAX_PARTITION_KEY_CHARS: usize = 256;
+
+#[derive(Args, Clone, PartialEq)]
+#[group()]
+pub struct KinesisArgs {
+    #[arg(
+        long,
+        help = "Enable uploading logs to AWS Kinesis Data Streams",
+        requires = "kinesis_stream_name"
+    )]
+    kinesis: bool,
+
+    #[arg(
+        long,
+        requires = "kinesis",
+        env = "KINESIS_STREAM_NAME",
+        hide_env_values = true
+    )]
+    kinesis_stream_name: Option<String>,
+
+    #[arg(
+        long,
+        requires = "kinesis",
+        default_value = "{hostname}",
+        value_parser = PartitionKeyTemplate::parse,
+        help = "Partition key, supports {hostname}, {timestamp} and {random}"
+    )]
+    kinesis_partition_key: PartitionKeyTemplate,
+
+    #[arg(
+        long,
+        requires = "kinesis",
+        help = "AWS region [default: from the environment]"
+    )]
+    kinesis_region: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Hostname,
+    Timestamp,
+    Random,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartitionKeyTemplate {
+    parts: Vec<Part>,
+}
+
+impl PartitionKeyTemplate {
+    fn parse(s: &str) -> Result<Self, String> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unterminated placeholder in '{}'", s))?;
+            parts.push(match &rest[start + 1..start + end] {
+                "hostname" => Part::Hostname,
+                "timestamp" => Part::Timestamp,
+                "random" => Part::Random,
+                other => return Err(format!("unknown placeholder {{{}}}", other)),
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        if parts.is_empty() {
+            return Err("partition key must not be empty".to_string());
+        }
+        Ok(Self { parts })
+    }
+
+    fn render(&self, hostname: &str, time: SystemTime) -> String {
+        let mut key = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => key.push_str(s),
+                Part::Hostname => key.push_str(hostname),
+                // times before the epoch, e.g. parsed from the logs, count as 0
+                Part::Timestamp => key.push_str(
+                    &time
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_millis())
+                        .to_string(),
+                ),
+                Part::Random => key.push_str(&RandomState::new().hash_one(0).to_string()),
+            }
+        }
+        key.chars().take(MAX_PARTITION_KEY_CHARS).collect()
+    }
+}
+
+/// Where the records are sent, if enabled.
+pub fn describe(args: &KinesisArgs) -> Option<String> {
+    if !args.kinesis {
+        return None;
+    }
+    let mut description = format!(
+        "Kinesis Data Stream {}",
+        args.kinesis_stream_name.as_deref()?
+    );
+    if let Some(region) = &args.kinesis_region {
+        description.push_str(&format!(", region {}", region));
+    }
+    Some(description)
+}
+
+/// Checks the credentials and the permission to describe the stream.
+pub async fn validate(args: &KinesisArgs) -> Result<String, String> {
+    let config = load_aws_config(0, args.kinesis_region.clone()).await;
+    let region = check_credentials(&config).await?;
+    let summary = Client::new(&config)
+        .describe_stream_summary()
+        .stream_name(args.kinesis_stream_name.as_deref().unwrap_or_default())
+        .send()
+        .await
+        .map_err(|e| format!("Error describing stream: {}", error_chain(&e)))?;
+    let status = summary
+        .stream_description_summary()
+        .map_or("unknown", |s| s.stream_status().as_str());
+    Ok(format!(
+        "credentials found in {}, stream is {}",
+        region, status
+    ))
+}
+
+pub struct KinesisWriter {
+    client: Client,
+    stream_name: String,
+    partition_key: PartitionKeyTemplate,
+    hostname: String,
+    max_retries: u32,
+    metrics: Arc<SinkMetrics>,
+    batch: Batch<PutRecordsRequestEntry>,
+}
+
+impl KinesisWriter {
+    pub async fn new(args: &KinesisArgs, max_retries: u32, name: &str) -> Option<Self> {
+        if !args.kinesis {
+            return None;
+        }
+
+        Some(Self {
+            client: Client::new(&load_aws_config(max_retries, args.kinesis_region.clone()).await),
+            stream_name: args.kinesis_stream_name.clone().unwrap(),
+            partition_key: args.kinesis_partition_key.clone(),
+            hostname: hostname::get().unwrap().into_string().unwrap(),
+            max_retries,
+            metrics: metrics().sink(name),
+            batch: Batch::new(MAX_BATCH_RECORDS, MAX_BATCH_BYTES),
+        })
+    }
+
+    /// Sends the pending batch, retrying only the records that failed, and
+    /// counts the records it could not send as dropped.
+    async fn send_batch(&mut self) {
+        let mut records = self.batch.take();
+        for attempt in 0..=self.max_retries {
+            if attempt > 0 {
+                self.metrics.retries.inc();
+                tokio::time::sleep(backoff(attempt - 1)).await;
+            }
+            self.metrics.batches_sent.inc();
+            let output = self
+                .client
+                .put_records()
+                .stream_name(&self.stream_name)
+                .set_records(Some(records.clone()))
+                .send()
+                .await;
+            let output = match output {
+                Ok(output) => output,
+                Err(e) => return self.drop_records(records.len(), &error_chain(&e)),
+            };
+            if output.failed_record_count().unwrap_or(0) == 0 {
+                return;
+            }
+            records = records
+                .into_iter()
+                .zip(output.records())
+                .filter(|(_, result)| result.error_code().is_some())
+                .map(|(record, _)| record)
+                .collect();
+        }
+        let reason = format!("still failing after {} retries", self.max_retries);
+        self.drop_records(records.len(), &reason);
+    }
+
+    fn drop_records(&self, count: usize, reason: &str) {
+        log_error!(
+            sink: self.metrics.name,
+            "Dropped {} Kinesis records for {}: {}",
+            count,
+            self.metrics.name,
+            reason
+        );
+        self.metrics.errors.inc();
+        self.metrics.dropped.add(count as u64);
+    }
+}
+
+#[async_trait]
+impl AsyncLogWriter for KinesisWriter {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let partition_key = self.partition_key.render(&self.hostname, record.timestamp);
+        let buf = record.to_bytes();
+        let data = &buf[..min(buf.len(), MAX_RECORD_BYTES - partition_key.len())];
+        let size = data.len() + partition_key.len();
+
+        if !self.batch.is_empty()
+            && (!self.batch.fits(size) || self.batch.is_expired(MAX_BATCH_DELAY))
+        {
+            self.send_batch().await;
+        }
+        self.batch.push(
+            PutRecordsRequestEntry::builder()
+                .data(Blob::new(data))
+                .partition_key(partition_key)
+                .build()
+                .unwrap(),
+            size,
+        );
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        if !self.batch.is_empty() {
+            self.send_batch().await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::serve_responses;
+    use aws_sdk_kinesis::config::{BehaviorVersion, Credentials, Region};
+    use serde_json::Value;
+    use std::time::Duration;
+
+    fn writer(endpoint: &str, max_retries: u32, max_records: usize) -> KinesisWriter {
+        let config = aws_sdk_kinesis::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("eu-west-1"))
+            .credentials_provider(Credentials::new("key", "secret", None, None, "test"))
+            .endpoint_url(endpoint)
+            .build();
+        KinesisWriter {
+            client: Client::from_conf(config),
+            stream_name: "logs".to_string(),
+            partition_key: PartitionKeyTemplate::parse("key").unwrap(),
+            hostname: "host".to_string(),
+            max_retries,
+            metrics: Arc::default(),
+            batch: Batch::new(max_records, MAX_BATCH_BYTES),
+        }
+    }
+
+    async fn write(writer: &mut KinesisWriter, message: &str) -> std::io::Result<()> {
+        let record = LogRecord::new(SystemTime::now(), message.as_bytes().to_vec());
+        writer.write_logs(&record).await
+    }
+
+    fn records(request: &Value) -> Vec<Value> {
+        request["Records"].as_array().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn retry_failed_records() {
+        let (endpoint, requests) = serve_responses(vec![
+            r#"{"FailedRecordCount":1,"Records":[{"SequenceNumber":"1","ShardId":"a"},{"ErrorCode":"ProvisionedThroughputExceededException","ErrorMessage":"slow down"}]}"#,
+            r#"{"FailedRecordCount":0,"Records":[{"SequenceNumber":"2","ShardId":"a"}]}"#,
+        ])
+        .await;
+        let mut writer = writer(&endpoint, 1, 2);
+
+        // the third record doesn't fit, sending the first two
+        write(&mut writer, "log1\n").await.unwrap();
+        write(&mut writer, "log2\n").await.unwrap();
+        write(&mut writer, "log3\n").await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0]["StreamName"], "logs");
+        assert_eq!(records(&requests[0]).len(), 2);
+        assert_eq!(
+            records(&requests[1]),
+            vec![records(&requests[0])[1].clone()]
+        );
+        assert_eq!(writer.metrics.retries.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn drop_records_after_max_retries() {
+        let failed = r#"{"FailedRecordCount":1,"Records":[{"ErrorCode":"InternalFailure","ErrorMessage":"oops"}]}"#;
+        let (endpoint, requests) = serve_responses(vec![failed, failed]).await;
+        let mut writer = writer(&endpoint, 1, MAX_BATCH_RECORDS);
+
+        write(&mut writer, "log1\n").await.unwrap();
+        writer.flush().await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert_eq!(writer.metrics.batches_sent.get(), 2);
+        assert_eq!(writer.metrics.dropped.get(), 1);
+        assert_eq!(writer.metrics.errors.get(), 1);
+        assert!(writer.batch.is_empty());
+    }
+
+    #[test]
+    fn render_partition_key() {
+        let time = UNIX_EPOCH + Duration::from_millis(1234);
+        let template = PartitionKeyTemplate::parse("logs-{hostname}-{timestamp}").unwrap();
+        assert_eq!(template.render("host", time), "logs-host-1234");
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(template.render("host", before_epoch), "logs-host-0");
+
+        let template = PartitionKeyTemplate::parse("{random}").unwrap();
+        assert_ne!(template.render("host", time), template.render("host", time));
+
+        assert!(PartitionKeyTemplate::parse("").is_err());
+        assert!(PartitionKeyTemplate::parse("{foo}").is_err());
+        assert!(PartitionKeyTemplate::parse("{hostname").is_err());
+    }
+
+    #[tokio::test]
+    async fn drop_the_batch_of_a_failed_request() {
+        let (endpoint, requests) = serve_responses(vec!["not json"]).await;
+        let mut writer = writer(&endpoint, 1, 2);
+
+        // the third record is not the one lost
+        write(&mut writer, "log1\n").await.unwrap();
+        write(&mut writer, "log2\n").await.unwrap();
+        write(&mut writer, "log3\n").await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(writer.metrics.dropped.get(), 2);
+        assert_eq!(writer.metrics.errors.get(), 1);
+        assert!(!writer.batch.is_empty());
+    }
+}
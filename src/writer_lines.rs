@@ -1,13 +1,15 @@
+use crate::record::LogRecord;
 use crate::writer::AsyncLogWriter;
 use async_trait::async_trait;
 use mem::take;
 use std::cmp::min;
 use std::mem;
-use std::time::SystemTime;
 
 pub struct LinesWriter<T: AsyncLogWriter> {
     inner: T,
     buf: Vec<u8>,
+    // metadata of the record the buffered partial line comes from
+    buf_record: Option<LogRecord>,
     max_line_size: usize,
 }
 
@@ -16,15 +18,33 @@ impl<T: AsyncLogWriter> LinesWriter<T> {
         Self {
             inner,
             buf: Vec::new(),
+            buf_record: None,
             max_line_size,
         }
     }
+
+    /// Takes the partial line, e.g. to hand it over to the writer replacing
+    /// this one on reload rather than sending it as a whole line.
+    pub fn take_partial(&mut self) -> Option<LogRecord> {
+        let record = self.buf_record.take()?;
+        let partial = take(&mut self.buf);
+        (!partial.is_empty()).then(|| record.with_message(partial))
+    }
+
+    /// Continues the partial line taken from the previous writer.
+    pub fn set_partial(&mut self, partial: Option<LogRecord>) {
+        if let Some(record) = partial {
+            let len = min(record.message.len(), self.max_line_size);
+            self.buf = record.message[..len].to_vec();
+            self.buf_record = Some(record.with_message(Vec::new()));
+        }
+    }
 }
 
 #[async_trait]
 impl<T: AsyncLogWriter + Send> AsyncLogWriter for LinesWriter<T> {
-    async fn write_logs(&mut self, time: SystemTime, buf: &[u8]) -> std::io::Result<()> {
-        let mut buf = buf;
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let mut buf = record.message.as_slice();
         while !buf.is_empty() {
             if let Some(pos) = buf.iter().position(|&b| b == b'\n') {
                 let len = min(self.buf.len() + pos + 1, self.max_line_size) - self.buf.len();
@@ -33,9 +53,12 @@ impl<T: AsyncLogWriter + Send> AsyncLogWriter for LinesWriter<T> {
                     let mut newbuf = take(&mut self.buf);
                     newbuf.extend_from_slice(line); // concatenate to previous buffer
                     self.buf.clear(); // clear before the next line could fail
-                    self.inner.write_logs(time, newbuf.as_slice()).await?;
+                    self.buf_record = None;
+                    self.inner.write_logs(&record.with_message(newbuf)).await?;
                 } else {
-                    self.inner.write_logs(time, line).await?;
+                    self.inner
+                        .write_logs(&record.with_message(line.to_vec()))
+                        .await?;
                 }
                 buf = &buf[len..];
             } else {
@@ -43,15 +66,34 @@ impl<T: AsyncLogWriter + Send> AsyncLogWriter for LinesWriter<T> {
                 let len = min(self.buf.len() + buf.len(), self.max_line_size) - self.buf.len();
                 buf = &buf[..len];
                 self.buf.extend_from_slice(buf);
+                self.buf_record
+                    .get_or_insert_with(|| record.with_message(Vec::new()));
                 buf = &buf[len..];
             }
         }
         Ok(())
     }
+
+    // keeps the partial line, which is only complete at the next newline
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        if let Some(record) = self.buf_record.take() {
+            if !self.buf.is_empty() {
+                self.inner
+                    .write_logs(&record.with_message(take(&mut self.buf)))
+                    .await?;
+            }
+        }
+        self.inner.close().await
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::record::LogRecord;
     use crate::writer::{AsyncLogWriter, MockAsyncLogWriter};
     use crate::writer_lines::LinesWriter;
     use mockall::predicate::eq;
@@ -63,41 +105,100 @@ mod tests {
         let time = SystemTime::now();
 
         mock.expect_write_logs()
-            .with(eq(time), eq(b"line1\n".to_vec()))
+            .with(eq(LogRecord::new(time, b"line1\n".to_vec())))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_| Ok(()));
         mock.expect_write_logs()
-            .with(eq(time), eq(b"line2\n".to_vec()))
+            .with(eq(LogRecord::new(time, b"line2\n".to_vec())))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_| Ok(()));
         mock.expect_write_logs()
-            .with(eq(time), eq(b"line3extra\n".to_vec()))
+            .with(eq(LogRecord::new(time, b"line3extra\n".to_vec())))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_| Ok(()));
         mock.expect_write_logs()
-            .with(eq(time), eq(b"veryveryveryver".to_vec()))
+            .with(eq(LogRecord::new(time, b"veryveryveryver".to_vec())))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_| Ok(()));
         mock.expect_write_logs()
-            .with(eq(time), eq(b"yverylonglonglo".to_vec()))
+            .with(eq(LogRecord::new(time, b"yverylonglonglo".to_vec())))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_| Ok(()));
         mock.expect_write_logs()
-            .with(eq(time), eq(b"ngline\n".to_vec()))
+            .with(eq(LogRecord::new(time, b"ngline\n".to_vec())))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_| Ok(()));
+        mock.expect_write_logs()
+            .with(eq(LogRecord::new(time, b"buffered".to_vec())))
+            .times(1)
+            .returning(|_| Ok(()));
         mock.expect_write_logs().times(0);
+        mock.expect_close().times(1).returning(|| Ok(()));
 
         let mut writer = LinesWriter::new(mock, 15);
         writer
-            .write_logs(time, b"line1\nline2\nline3")
+            .write_logs(&LogRecord::new(time, b"line1\nline2\nline3".to_vec()))
             .await
             .unwrap();
         writer
-            .write_logs(
+            .write_logs(&LogRecord::new(
                 time,
-                b"extra\nveryveryveryveryverylonglonglongline\nbuffered",
-            )
+                b"extra\nveryveryveryveryverylonglonglongline\nbuffered".to_vec(),
+            ))
+            .await
+            .unwrap();
+        writer.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn flush_keeps_partial_line() {
+        let mut mock = MockAsyncLogWriter::new();
+        let time = SystemTime::now();
+
+        mock.expect_flush().times(1).returning(|| Ok(()));
+        mock.expect_write_logs()
+            .with(eq(LogRecord::new(time, b"partial line\n".to_vec())))
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_write_logs().times(0);
+
+        let mut writer = LinesWriter::new(mock, 100);
+        writer
+            .write_logs(&LogRecord::new(time, b"partial".to_vec()))
+            .await
+            .unwrap();
+        writer.flush().await.unwrap();
+        writer
+            .write_logs(&LogRecord::new(time, b" line\n".to_vec()))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn hand_over_partial_line() {
+        let mut previous = MockAsyncLogWriter::new();
+        let mut next = MockAsyncLogWriter::new();
+        let time = SystemTime::now();
+
+        previous.expect_write_logs().times(0);
+        previous.expect_close().times(1).returning(|| Ok(()));
+        next.expect_write_logs()
+            .with(eq(LogRecord::new(time, b"partial line\n".to_vec())))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut writer = LinesWriter::new(previous, 100);
+        writer
+            .write_logs(&LogRecord::new(time, b"partial".to_vec()))
+            .await
+            .unwrap();
+        let partial = writer.take_partial();
+        writer.close().await.unwrap();
+
+        let mut writer = LinesWriter::new(next, 100);
+        writer.set_partial(partial);
+        writer
+            .write_logs(&LogRecord::new(time, b" line\n".to_vec()))
             .await
             .unwrap();
     }
@@ -108,24 +209,27 @@ mod tests {
         let time = SystemTime::now();
 
         mock.expect_write_logs()
-            .with(eq(time), eq(b"foof".to_vec()))
+            .with(eq(LogRecord::new(time, b"foof".to_vec())))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_| Ok(()));
         mock.expect_write_logs()
-            .with(eq(time), eq(b"oo\n".to_vec()))
+            .with(eq(LogRecord::new(time, b"oo\n".to_vec())))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_| Ok(()));
         mock.expect_write_logs()
-            .with(eq(time), eq(b"barb".to_vec()))
+            .with(eq(LogRecord::new(time, b"barb".to_vec())))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_| Ok(()));
         mock.expect_write_logs()
-            .with(eq(time), eq(b"ar\n".to_vec()))
+            .with(eq(LogRecord::new(time, b"ar\n".to_vec())))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_| Ok(()));
         mock.expect_write_logs().times(0);
 
         let mut writer = LinesWriter::new(mock, 4);
-        writer.write_logs(time, b"foofoo\nbarbar\n").await.unwrap();
+        writer
+            .write_logs(&LogRecord::new(time, b"foofoo\nbarbar\n".to_vec()))
+            .await
+            .unwrap();
     }
 }
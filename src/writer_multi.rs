@@ -1,6 +1,6 @@
+use crate::record::LogRecord;
 use crate::writer::AsyncLogWriter;
 use async_trait::async_trait;
-use std::time::SystemTime;
 
 pub struct MultiWriter<T>
 where
@@ -15,13 +15,43 @@ impl<T: AsyncLogWriter> MultiWriter<T> {
     }
 }
 
+impl<T: AsyncLogWriter + Send> MultiWriter<T> {
+    /// Writes to the writers whose index is selected.
+    pub async fn write_selected(
+        &mut self,
+        record: &LogRecord,
+        selected: impl Fn(usize) -> bool,
+    ) -> std::io::Result<()> {
+        for (i, writer) in self.writers.iter_mut().enumerate() {
+            if selected(i) {
+                writer.write_logs(record).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl<T: AsyncLogWriter + Send> AsyncLogWriter for MultiWriter<T> {
-    async fn write_logs(&mut self, time: SystemTime, buf: &[u8]) -> std::io::Result<()> {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        self.write_selected(record, |_| true).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        // give every writer a chance to flush, then report the first error
+        let mut result = Ok(());
         for writer in self.writers.iter_mut() {
-            writer.write_logs(time, buf).await?;
+            result = result.and(writer.flush().await);
         }
-        Ok(())
+        result
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        let mut result = Ok(());
+        for writer in self.writers.iter_mut() {
+            result = result.and(writer.close().await);
+        }
+        result
     }
 }
 
@@ -31,7 +61,7 @@ mod tests {
     use crate::writer::MockAsyncLogWriter;
     use mockall::predicate::*;
     use std::ops::Add;
-    use std::time::Duration;
+    use std::time::{Duration, SystemTime};
 
     #[tokio::test]
     async fn test_write_to_multiple_writers() {
@@ -39,31 +69,46 @@ mod tests {
         let mut mock_writer2 = MockAsyncLogWriter::new();
 
         let time1 = SystemTime::now();
-        let buf1: &[u8] = b"test1";
+        let record1 = LogRecord::new(time1, b"test1".to_vec());
         let time2 = time1.add(Duration::new(100, 0));
-        let buf2: &[u8] = b"test2";
+        let record2 = LogRecord::new(time2, b"test2".to_vec());
         mock_writer1
             .expect_write_logs()
-            .with(eq(time1), eq(buf1))
-            .returning(|_, _| Ok(()));
+            .with(eq(record1.clone()))
+            .returning(|_| Ok(()));
         mock_writer1
             .expect_write_logs()
-            .with(eq(time2), eq(buf2))
-            .returning(|_, _| Ok(()));
+            .with(eq(record2.clone()))
+            .returning(|_| Ok(()));
         mock_writer2
             .expect_write_logs()
-            .with(eq(time1), eq(buf1))
-            .returning(|_, _| Ok(()));
+            .with(eq(record1.clone()))
+            .returning(|_| Ok(()));
         mock_writer2
             .expect_write_logs()
-            .with(eq(time2), eq(buf2))
-            .returning(|_, _| Ok(()));
+            .with(eq(record2.clone()))
+            .returning(|_| Ok(()));
 
         let mut multi_writer = MultiWriter::new(vec![mock_writer1, mock_writer2]);
 
-        let result = multi_writer.write_logs(time1, buf1).await;
+        let result = multi_writer.write_logs(&record1).await;
         assert!(result.is_ok());
-        let result = multi_writer.write_logs(time2, buf2).await;
+        let result = multi_writer.write_logs(&record2).await;
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn close_all_writers_on_error() {
+        let mut mock_writer1 = MockAsyncLogWriter::new();
+        let mut mock_writer2 = MockAsyncLogWriter::new();
+
+        mock_writer1
+            .expect_close()
+            .times(1)
+            .returning(|| Err(std::io::Error::other("Error")));
+        mock_writer2.expect_close().times(1).returning(|| Ok(()));
+
+        let mut multi_writer = MultiWriter::new(vec![mock_writer1, mock_writer2]);
+        assert!(multi_writer.close().await.is_err());
+    }
 }
@@ -0,0 +1,366 @@
+use crate::record::LogRecord;
+use crate::writer::AsyncLogWriter;
+use async_trait::async_trait;
+use clap::{Args, ValueEnum};
+use regex::bytes::Regex;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum MultilinePreset {
+    Java,
+    Python,
+    Go,
+    Rust,
+}
+
+impl MultilinePreset {
+    fn continuation(&self) -> &'static str {
+        match self {
+            // indented "at ..." frames, "... 3 more", "Caused by:" and "Suppressed:"
+            MultilinePreset::Java => r"^(\s|Caused by:|Suppressed:)",
+            // traceback header and frames, chained tracebacks and the final exception
+            MultilinePreset::Python => {
+                r"^(\s|Traceback \(most recent call last\):|During handling of the above exception|The above exception was the direct cause|[\w.]*(Error|Exception|Exit|Interrupt|Warning)(:|$))"
+            }
+            // goroutine dumps with function calls, file locations and blank separators
+            MultilinePreset::Go => {
+                r"^(\s|$|goroutine \d+ \[|created by |[\w./*()\[\]-]+\(.*\)$|exit status \d+)"
+            }
+            // panic notes and indented backtrace frames
+            MultilinePreset::Rust => r"^(\s|stack backtrace:|note: )",
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct MultilineArgs {
+    #[arg(
+        long,
+        value_enum,
+        help = "Join the lines of stack traces into a single log"
+    )]
+    multiline: Option<MultilinePreset>,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Lines matching the regex start a new multiline log"
+    )]
+    multiline_start: Option<Regex>,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Lines matching the regex are joined to the previous line"
+    )]
+    multiline_continue: Option<Regex>,
+
+    #[arg(long, default_value = "500", help = "Max lines of a multiline log")]
+    multiline_max_lines: usize,
+
+    #[arg(long, default_value = "1000000", help = "Max size of a multiline log")]
+    multiline_max_bytes: usize,
+
+    #[arg(
+        long,
+        value_name = "MILLIS",
+        default_value = "1000",
+        help = "Send the pending multiline log when no input arrives within the timeout"
+    )]
+    multiline_flush_timeout: u64,
+}
+
+pub struct MultilineRules {
+    start: Option<Regex>,
+    continuation: Option<Regex>,
+    max_lines: usize,
+    max_bytes: usize,
+    pub flush_timeout: Duration,
+}
+
+impl MultilineRules {
+    pub fn new(args: &MultilineArgs) -> Option<Self> {
+        let continuation = match (&args.multiline_continue, args.multiline) {
+            (Some(regex), _) => Some(regex.clone()),
+            (None, Some(preset)) => Some(Regex::new(preset.continuation()).unwrap()),
+            (None, None) => None,
+        };
+        if continuation.is_none() && args.multiline_start.is_none() {
+            return None;
+        }
+        Some(Self {
+            start: args.multiline_start.clone(),
+            continuation,
+            max_lines: args.multiline_max_lines,
+            max_bytes: args.multiline_max_bytes,
+            flush_timeout: Duration::from_millis(args.multiline_flush_timeout),
+        })
+    }
+
+    fn is_continuation(&self, line: &[u8]) -> bool {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        if let Some(continuation) = &self.continuation {
+            if continuation.is_match(line) {
+                return true;
+            }
+        }
+        match &self.start {
+            Some(start) => !start.is_match(line),
+            None => false,
+        }
+    }
+}
+
+/// Joins lines into a single log according to the rules, e.g. for stack traces.
+pub struct MultilineWriter<T: AsyncLogWriter> {
+    inner: T,
+    rules: MultilineRules,
+    pending: Option<LogRecord>,
+    lines: usize,
+    // when the last line was added to the pending log
+    extended: Instant,
+}
+
+impl<T: AsyncLogWriter> MultilineWriter<T> {
+    pub fn new(inner: T, rules: MultilineRules) -> Self {
+        Self {
+            inner,
+            rules,
+            pending: None,
+            lines: 0,
+            extended: Instant::now(),
+        }
+    }
+}
+
+impl<T: AsyncLogWriter + Send> MultilineWriter<T> {
+    async fn write_pending(&mut self) -> std::io::Result<()> {
+        self.lines = 0;
+        match self.pending.take() {
+            Some(record) => self.inner.write_logs(&record).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: AsyncLogWriter + Send> AsyncLogWriter for MultilineWriter<T> {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        if let Some(pending) = &mut self.pending {
+            if self.rules.is_continuation(&record.message)
+                && self.lines < self.rules.max_lines
+                && pending.message.len() + record.message.len() <= self.rules.max_bytes
+            {
+                pending.message.extend_from_slice(&record.message);
+                self.lines += 1;
+                self.extended = Instant::now();
+                return Ok(());
+            }
+        }
+
+        let result = self.write_pending().await;
+        self.pending = Some(record.with_message(record.message.clone()));
+        self.lines = 1;
+        self.extended = Instant::now();
+        result
+    }
+
+    /// Sends the pending log only once no line was added within the timeout,
+    /// as the flushes of the other stages may come sooner.
+    async fn flush(&mut self) -> std::io::Result<()> {
+        if self.extended.elapsed() >= self.rules.flush_timeout {
+            self.write_pending().await?;
+        }
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        self.write_pending().await?;
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::MockAsyncLogWriter;
+    use mockall::predicate::eq;
+    use std::time::SystemTime;
+
+    fn args(preset: Option<MultilinePreset>) -> MultilineArgs {
+        MultilineArgs {
+            multiline: preset,
+            multiline_start: None,
+            multiline_continue: None,
+            multiline_max_lines: 3,
+            multiline_max_bytes: 1000000,
+            multiline_flush_timeout: 1000,
+        }
+    }
+
+    fn is_continuation(preset: MultilinePreset, line: &str) -> bool {
+        MultilineRules::new(&args(Some(preset)))
+            .unwrap()
+            .is_continuation(line.as_bytes())
+    }
+
+    #[test]
+    fn match_presets() {
+        assert!(is_continuation(
+            MultilinePreset::Java,
+            "\tat com.example.Foo.bar(Foo.java:42)\n"
+        ));
+        assert!(is_continuation(
+            MultilinePreset::Java,
+            "Caused by: java.io.IOException: boom\n"
+        ));
+        assert!(!is_continuation(
+            MultilinePreset::Java,
+            "2024-01-01 INFO started\n"
+        ));
+
+        assert!(is_continuation(
+            MultilinePreset::Python,
+            "Traceback (most recent call last):\n"
+        ));
+        assert!(is_continuation(
+            MultilinePreset::Python,
+            "  File \"foo.py\", line 1, in <module>\n"
+        ));
+        assert!(is_continuation(
+            MultilinePreset::Python,
+            "ValueError: invalid literal\n"
+        ));
+        assert!(!is_continuation(
+            MultilinePreset::Python,
+            "INFO:root:started\n"
+        ));
+
+        assert!(is_continuation(
+            MultilinePreset::Go,
+            "goroutine 1 [running]:\n"
+        ));
+        assert!(is_continuation(MultilinePreset::Go, "main.main()\n"));
+        assert!(is_continuation(
+            MultilinePreset::Go,
+            "\t/app/main.go:12 +0x1d\n"
+        ));
+        assert!(!is_continuation(
+            MultilinePreset::Go,
+            "level=info msg=started\n"
+        ));
+
+        assert!(is_continuation(MultilinePreset::Rust, "stack backtrace:\n"));
+        assert!(is_continuation(
+            MultilinePreset::Rust,
+            "   0: rust_begin_unwind\n"
+        ));
+        assert!(is_continuation(
+            MultilinePreset::Rust,
+            "note: run with `RUST_BACKTRACE=1`\n"
+        ));
+        assert!(!is_continuation(
+            MultilinePreset::Rust,
+            "thread 'main' panicked at src/main.rs:2:5:\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn join_lines() {
+        let mut mock = MockAsyncLogWriter::new();
+        let time = SystemTime::now();
+
+        mock.expect_write_logs()
+            .with(eq(LogRecord::new(
+                time,
+                b"Exception: boom\n\tat Foo.bar\n\tat Foo.main\n".to_vec(),
+            )))
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_write_logs()
+            .with(eq(LogRecord::new(time, b"\tat Thread.run\n".to_vec())))
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_write_logs()
+            .with(eq(LogRecord::new(time, b"next\n".to_vec())))
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_write_logs().times(0);
+        mock.expect_flush().times(1).returning(|| Ok(()));
+
+        let mut rules = MultilineRules::new(&args(Some(MultilinePreset::Java))).unwrap();
+        rules.flush_timeout = Duration::ZERO;
+        let mut writer = MultilineWriter::new(mock, rules);
+        // the fourth line exceeds the max lines
+        for line in [
+            "Exception: boom\n",
+            "\tat Foo.bar\n",
+            "\tat Foo.main\n",
+            "\tat Thread.run\n",
+            "next\n",
+        ] {
+            writer
+                .write_logs(&LogRecord::new(time, line.as_bytes().to_vec()))
+                .await
+                .unwrap();
+        }
+        writer.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn start_pattern() {
+        let mut mock = MockAsyncLogWriter::new();
+        let time = SystemTime::now();
+
+        mock.expect_write_logs()
+            .with(eq(LogRecord::new(time, b"[1] foo\nbar\n".to_vec())))
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_write_logs()
+            .with(eq(LogRecord::new(time, b"[2] baz\n".to_vec())))
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_close().times(1).returning(|| Ok(()));
+
+        let mut args = args(None);
+        args.multiline_start = Some(Regex::new(r"^\[\d+\]").unwrap());
+        let mut writer = MultilineWriter::new(mock, MultilineRules::new(&args).unwrap());
+        for line in ["[1] foo\n", "bar\n", "[2] baz\n"] {
+            writer
+                .write_logs(&LogRecord::new(time, line.as_bytes().to_vec()))
+                .await
+                .unwrap();
+        }
+        writer.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keep_pending_log_until_timeout() {
+        let mut mock = MockAsyncLogWriter::new();
+        let time = SystemTime::now();
+
+        mock.expect_flush().times(1).returning(|| Ok(()));
+        mock.expect_write_logs()
+            .with(eq(LogRecord::new(
+                time,
+                b"Exception: boom\n\tat Foo.bar\n".to_vec(),
+            )))
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_close().times(1).returning(|| Ok(()));
+
+        let rules = MultilineRules::new(&args(Some(MultilinePreset::Java))).unwrap();
+        let mut writer = MultilineWriter::new(mock, rules);
+        for line in ["Exception: boom\n", "\tat Foo.bar\n"] {
+            writer
+                .write_logs(&LogRecord::new(time, line.as_bytes().to_vec()))
+                .await
+                .unwrap();
+            // e.g. the flush of the stdout redaction, well within the timeout
+            if line.starts_with("Exception") {
+                writer.flush().await.unwrap();
+            }
+        }
+        writer.close().await.unwrap();
+    }
+}
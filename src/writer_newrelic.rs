@@ -1,9 +1,13 @@
+use crate::diag::error_chain;
+use crate::metrics::{metrics, SinkMetrics};
+use crate::record::LogRecord;
 use crate::writer::AsyncLogWriter;
 use async_trait::async_trait;
 use clap::{Args, ValueEnum};
-use std::time::SystemTime;
+use reqwest::StatusCode;
+use std::sync::Arc;
 
-#[derive(Args)]
+#[derive(Args, Clone, PartialEq)]
 #[group()]
 pub struct NewRelicArgs {
     #[arg(
@@ -41,43 +45,125 @@ enum NewRelicRegion {
     EU,
 }
 
+fn endpoint(region: NewRelicRegion) -> &'static str {
+    match region {
+        NewRelicRegion::US => "https://log-api.newrelic.com/log/v1",
+        NewRelicRegion::EU => "https://log-api.eu.newrelic.com/log/v1",
+    }
+}
+
+/// Where the logs are sent, if enabled.
+pub fn describe(args: &NewRelicArgs) -> Option<String> {
+    if !args.newrelic {
+        return None;
+    }
+    Some(format!("NewRelic {}", endpoint(args.newrelic_region?)))
+}
+
+/// Checks the API key by sending an empty list of logs.
+pub async fn validate(args: &NewRelicArgs) -> Result<String, String> {
+    let response = reqwest::Client::new()
+        .post(endpoint(args.newrelic_region.ok_or("no region")?))
+        .header(
+            "Api-Key",
+            args.newrelic_api_key.as_deref().unwrap_or_default(),
+        )
+        .json(&serde_json::json!([]))
+        .send()
+        .await
+        .map_err(|e| format!("Error reaching NewRelic: {}", error_chain(&e)))?;
+    match response.status() {
+        status if status.is_success() => Ok("API key accepted".to_string()),
+        status @ (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+            Err(format!("API key rejected: {}", status))
+        }
+        status => Err(format!("Unexpected response: {}", status)),
+    }
+}
+
 pub struct NewRelicWriter {
     client: reqwest::Client,
     endpoint: String,
     api_key: String,
+    metrics: Arc<SinkMetrics>,
+}
+
+/// Attributes with a name of their own in NewRelic.
+const NATIVE_ATTRIBUTES: [(&str, &str); 1] = [("host.name", "hostname")];
+
+/// The log with its attributes as top-level fields.
+fn to_json(record: &LogRecord) -> serde_json::Value {
+    let mut json = record.to_json();
+    if let Some(fields) = json.as_object_mut() {
+        for (key, native) in NATIVE_ATTRIBUTES {
+            if !fields.contains_key(native) {
+                if let Some(value) = fields.remove(key) {
+                    fields.insert(native.to_string(), value);
+                }
+            }
+        }
+    }
+    json
 }
 
 impl NewRelicWriter {
-    pub fn new(args: &NewRelicArgs) -> Option<Self> {
+    pub fn new(args: &NewRelicArgs, name: &str) -> Option<Self> {
         if !args.newrelic {
             return None;
         }
 
         Some(Self {
             client: reqwest::Client::new(),
-            endpoint: match args.newrelic_region.as_ref()? {
-                NewRelicRegion::US => "https://log-api.newrelic.com/log/v1".to_string(),
-                NewRelicRegion::EU => "https://log-api.eu.newrelic.com/log/v1".to_string(),
-            },
+            endpoint: endpoint(args.newrelic_region?).to_string(),
             api_key: args.newrelic_api_key.as_ref()?.to_string(),
+            metrics: metrics().sink(name),
         })
     }
 }
 
 #[async_trait]
 impl AsyncLogWriter for NewRelicWriter {
-    async fn write_logs(&mut self, time: SystemTime, buf: &[u8]) -> std::io::Result<()> {
-        let json = serde_json::json!({
-            "timestamp": time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis(),
-            "message": String::from_utf8_lossy(buf).to_string()
-        });
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let json = to_json(record);
+        self.metrics.batches_sent.inc();
         self.client
             .post(&self.endpoint)
             .header("Api-Key", &self.api_key)
             .json(&json)
             .send()
             .await
-            .unwrap();
+            .and_then(|response| response.error_for_status())
+            .map_err(std::io::Error::other)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::SystemTime;
+
+    #[test]
+    fn native_attributes() {
+        let mut record = LogRecord::new(SystemTime::now(), b"hello\n".to_vec());
+        record
+            .attributes
+            .insert("host.name".to_string(), json!("web-1"));
+        record
+            .attributes
+            .insert("process.pid".to_string(), json!(1234));
+        let json = to_json(&record);
+        assert_eq!(json["hostname"], json!("web-1"));
+        assert_eq!(json["process.pid"], json!(1234));
+        assert!(json.get("host.name").is_none());
+
+        // a hostname attribute of the app wins
+        record
+            .attributes
+            .insert("hostname".to_string(), json!("app"));
+        let json = to_json(&record);
+        assert_eq!(json["hostname"], json!("app"));
+        assert_eq!(json["host.name"], json!("web-1"));
+    }
+}
@@ -1,62 +1,611 @@
+use crate::diag::error_chain;
+use crate::metrics::SinkMetrics;
+use crate::record::LogRecord;
+use crate::spill::Spill;
 use crate::writer::AsyncLogWriter;
+use crate::{log_error, log_warn};
 use async_trait::async_trait;
-use std::time::SystemTime;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::error::TrySendError::{Closed, Full};
+use clap::{Args, ValueEnum};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, Notify};
 use tokio::task::JoinHandle;
 
-struct LogEvent {
-    timestamp: SystemTime,
-    message: Vec<u8>,
+/// What to do with a log when the queue of its sink is full.
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum Overflow {
+    DropNewest,
+    DropOldest,
+    // wait for room, slowing down the input and thus the app
+    Block,
+    SpillToDisk,
+}
+
+/// The overflow policy of a sink, or of all the sinks.
+#[derive(Clone, Debug)]
+pub struct OverflowRule {
+    sink: Option<String>,
+    overflow: Overflow,
+}
+
+impl OverflowRule {
+    /// Parses `[SINK:]POLICY`, e.g. `aws:block`.
+    fn parse(rule: &str) -> Result<Self, String> {
+        let (sink, overflow) = match rule.split_once(':') {
+            Some((sink, overflow)) => (Some(sink.to_string()), overflow),
+            None => (None, rule),
+        };
+        Ok(Self {
+            sink,
+            overflow: Overflow::from_str(overflow, true)?,
+        })
+    }
+
+    pub fn sink(&self) -> Option<&str> {
+        self.sink.as_deref()
+    }
+}
+
+/// A memory limit of a sink, or of all the sinks together.
+#[derive(Clone, Debug)]
+pub struct MemoryRule {
+    sink: Option<String>,
+    bytes: usize,
+}
+
+impl MemoryRule {
+    /// Parses `[SINK:]BYTES`, e.g. `aws:10000000`.
+    fn parse(rule: &str) -> Result<Self, String> {
+        let (sink, bytes) = match rule.split_once(':') {
+            Some((sink, bytes)) => (Some(sink.to_string()), bytes),
+            None => (None, rule),
+        };
+        let bytes = bytes
+            .parse()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("expected a positive integer, got {}", bytes))?;
+        Ok(Self { sink, bytes })
+    }
+
+    pub fn sink(&self) -> Option<&str> {
+        self.sink.as_deref()
+    }
+}
+
+#[derive(Args)]
+pub struct QueueArgs {
+    #[arg(
+        long,
+        help = "Max logs to keep in memory for each sink before applying the overflow policy",
+        default_value = "1000"
+    )]
+    pub max_memory_items: usize,
+
+    #[arg(
+        long,
+        value_name = "[SINK:]BYTES",
+        value_parser = MemoryRule::parse,
+        help = "Max bytes of logs to keep in memory for all the sinks together, or for the given sink, before applying the overflow policy. Each sink keeps at least one log whatever its size"
+    )]
+    pub max_memory_bytes: Vec<MemoryRule>,
+
+    #[arg(
+        long,
+        value_name = "[SINK:]POLICY",
+        value_parser = OverflowRule::parse,
+        help = "What to do with the logs when the queue of the sink is full: drop-newest, drop-oldest, block the input and so stdout and the app, or spill-to-disk [default: drop-newest]"
+    )]
+    pub overflow: Vec<OverflowRule>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = "/var/spool/logup",
+        help = "Directory of the logs spilled to disk, kept across restarts. The spill files are not bounded, they grow for as long as the sink is slower than the input, and each logup needs its own directory. After a crash, the logs read back since the file was last emptied are sent again"
+    )]
+    pub spill_dir: PathBuf,
+}
+
+impl QueueArgs {
+    /// The memory limit of the sink, or of all the sinks together for None.
+    pub fn max_memory_bytes(&self, sink: Option<&str>) -> Option<usize> {
+        let mut rules = self.max_memory_bytes.iter().rev();
+        rules
+            .find(|rule| rule.sink() == sink)
+            .map(|rule| rule.bytes)
+    }
+
+    pub fn limits(&self, sink: &str, budget: &Arc<MemoryBudget>) -> QueueLimits {
+        QueueLimits {
+            items: self.max_memory_items,
+            bytes: self.max_memory_bytes(Some(sink)),
+            budget: budget.clone(),
+        }
+    }
+
+    /// The policy of the sink, the rules of the sink itself taking precedence.
+    pub fn overflow(&self, sink: &str) -> Overflow {
+        let rules = self.overflow.iter().rev();
+        let mut rules = rules.filter(|rule| rule.sink.as_ref().is_none_or(|s| s == sink));
+        rules
+            .clone()
+            .find(|rule| rule.sink.is_some())
+            .or_else(|| rules.next())
+            .map_or(Overflow::DropNewest, |rule| rule.overflow)
+    }
+
+    /// Opens the spill file of the sink if it spills to disk.
+    pub async fn policy(&self, sink: &str) -> std::io::Result<Policy> {
+        Ok(match self.overflow(sink) {
+            Overflow::DropNewest => Policy::DropNewest,
+            Overflow::DropOldest => Policy::DropOldest,
+            Overflow::Block => Policy::Block,
+            Overflow::SpillToDisk => {
+                let name: String = sink
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                    .collect();
+                Policy::Spill(Spill::open(&self.spill_dir.join(format!("{}.spill", name))).await?)
+            }
+        })
+    }
+}
+
+/// Bytes of the logs in the queues of all the sinks.
+#[derive(Default)]
+pub struct MemoryBudget {
+    /// Zero for no limit.
+    limit: AtomicUsize,
+    used: AtomicUsize,
+    /// Wakes a blocked writer when any of the queues takes a message.
+    freed: Notify,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        let budget = Self::default();
+        budget.set_limit(limit);
+        budget
+    }
+
+    /// Changes the limit, e.g. on reload, keeping the logs already queued.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit.store(limit.unwrap_or(0), Relaxed);
+    }
+
+    fn fits(&self, size: usize) -> bool {
+        let limit = self.limit.load(Relaxed);
+        limit == 0 || self.used.load(Relaxed) + size <= limit
+    }
+}
+
+/// Bounds of a queue, besides the budget shared with the other queues.
+pub struct QueueLimits {
+    pub items: usize,
+    pub bytes: Option<usize>,
+    pub budget: Arc<MemoryBudget>,
+}
+
+/// An overflow policy with its state.
+pub enum Policy {
+    DropNewest,
+    DropOldest,
+    Block,
+    Spill(Spill),
+}
+
+enum QueueMessage {
+    Record(LogRecord),
+    Flush,
+    Close(oneshot::Sender<std::io::Result<()>>),
+}
+
+struct State {
+    messages: VecDeque<QueueMessage>,
+    /// Records among the messages and their size, bounded by the limits.
+    records: usize,
+    bytes: usize,
+    policy: Policy,
+    /// The queue closes once all the writers are dropped.
+    writers: usize,
+    closed: bool,
+}
+
+impl State {
+    /// Records in the spill file, if any.
+    fn spilled(&self) -> usize {
+        match &self.policy {
+            Policy::Spill(spill) => spill.pending(),
+            _ => 0,
+        }
+    }
+}
+
+struct Queue {
+    state: Mutex<State>,
+    limits: QueueLimits,
+    /// Wakes the consumer task when a message is queued.
+    queued: Notify,
+}
+
+impl Queue {
+    /// The next message in order, or None once all the writers are dropped.
+    async fn next(&self, metrics: &SinkMetrics) -> Option<QueueMessage> {
+        loop {
+            let spill = {
+                let mut state = self.state.lock().unwrap();
+                // spilled records are older than the records queued after them
+                let spill = match &state.policy {
+                    Policy::Spill(spill)
+                        if spill.pending() > 0
+                            && !matches!(state.messages.front(), Some(QueueMessage::Record(_))) =>
+                    {
+                        Some(spill.clone())
+                    }
+                    _ => None,
+                };
+                if spill.is_none() {
+                    if let Some(message) = self.take(&mut state, metrics) {
+                        self.limits.budget.freed.notify_one();
+                        return Some(message);
+                    }
+                    if state.writers == 0 {
+                        state.closed = true;
+                        return None;
+                    }
+                }
+                spill
+            };
+            match spill {
+                Some(spill) => {
+                    if let Some(record) = Self::pop_spilled(&spill, metrics).await {
+                        return Some(QueueMessage::Record(record));
+                    }
+                    // a spilled log could not be read, try the next one
+                }
+                None => self.queued.notified().await,
+            }
+        }
+    }
+
+    /// Whether a record of the given size can be queued within the limits.
+    fn fits(&self, state: &State, size: usize) -> bool {
+        // at least one log per sink, so that a large one doesn't stop the sink
+        state.records == 0
+            || (state.records < self.limits.items
+                && self
+                    .limits
+                    .bytes
+                    .is_none_or(|max| state.bytes + size <= max)
+                && self.limits.budget.fits(size))
+    }
+
+    fn push(&self, state: &mut State, record: &LogRecord, metrics: &SinkMetrics) {
+        let size = record.size();
+        state
+            .messages
+            .push_back(QueueMessage::Record(record.clone()));
+        state.records += 1;
+        state.bytes += size;
+        self.limits.budget.used.fetch_add(size, Relaxed);
+        metrics.queue_depth.inc();
+        metrics.queue_bytes.add(size as i64);
+    }
+
+    /// Removes the oldest record, returning whether there was one.
+    fn remove_oldest(&self, state: &mut State, metrics: &SinkMetrics) -> bool {
+        let oldest = state
+            .messages
+            .iter()
+            .position(|message| matches!(message, QueueMessage::Record(_)));
+        let Some(QueueMessage::Record(record)) = oldest.and_then(|i| state.messages.remove(i))
+        else {
+            return false;
+        };
+        self.release(state, &record, metrics);
+        metrics.queue_depth.dec();
+        metrics.dropped.inc();
+        true
+    }
+
+    fn release(&self, state: &mut State, record: &LogRecord, metrics: &SinkMetrics) {
+        let size = record.size();
+        state.records -= 1;
+        state.bytes -= size;
+        self.limits.budget.used.fetch_sub(size, Relaxed);
+        metrics.queue_bytes.add(-(size as i64));
+    }
+
+    /// The oldest spilled record, or None if it could not be read.
+    async fn pop_spilled(spill: &Spill, metrics: &SinkMetrics) -> Option<LogRecord> {
+        metrics.spilled.dec();
+        match spill.pop().await {
+            Ok(record) => {
+                metrics.queue_depth.inc();
+                record
+            }
+            Err(e) => {
+                log_error!(
+                    sink: metrics.name,
+                    "Dropping a spilled log for {}: {}",
+                    metrics.name,
+                    error_chain(&e)
+                );
+                metrics.errors.inc();
+                metrics.dropped.inc();
+                None
+            }
+        }
+    }
+
+    /// The next message in memory, if any.
+    fn take(&self, state: &mut State, metrics: &SinkMetrics) -> Option<QueueMessage> {
+        let message = state.messages.pop_front()?;
+        if let QueueMessage::Record(record) = &message {
+            self.release(state, record, metrics);
+        }
+        Some(message)
+    }
 }
 
 pub struct QueueWriter {
-    tx: mpsc::Sender<LogEvent>,
+    queue: Arc<Queue>,
+    metrics: Arc<SinkMetrics>,
+    // shared writers only flush on close
+    owned: bool,
 }
 
 impl QueueWriter {
     pub fn new<T: AsyncLogWriter + Send + 'static>(
         mut inner: T,
-        limit: usize,
+        limits: QueueLimits,
+        policy: Policy,
+        metrics: Arc<SinkMetrics>,
     ) -> (Self, JoinHandle<()>) {
-        // TODO: implement channel bounded based on memory size rather than number of elements
-        let (tx, mut rx) = mpsc::channel::<LogEvent>(limit);
+        if let Policy::Spill(spill) = &policy {
+            metrics.spilled.set(spill.pending() as i64);
+        }
+        let queue = Arc::new(Queue {
+            state: Mutex::new(State {
+                messages: VecDeque::new(),
+                records: 0,
+                bytes: 0,
+                policy,
+                writers: 1,
+                closed: false,
+            }),
+            limits,
+            queued: Notify::new(),
+        });
 
+        let task_queue = queue.clone();
+        let task_metrics = metrics.clone();
         let handle = tokio::spawn(async move {
-            while let Some(event) = rx.recv().await {
-                // TODO: log dropped message
-                // retries must be handled downstream
-                let _ = inner.write_logs(event.timestamp, &event.message).await;
+            while let Some(message) = task_queue.next(&task_metrics).await {
+                match message {
+                    QueueMessage::Record(record) => {
+                        // retries must be handled downstream
+                        match inner.write_logs(&record).await {
+                            Ok(()) => {
+                                task_metrics.lines_written.inc();
+                                if let Ok(latency) = record.observed_timestamp.elapsed() {
+                                    task_metrics.latency.observe(latency);
+                                }
+                            }
+                            Err(e) => {
+                                log_error!(
+                                    sink: task_metrics.name,
+                                    "Dropping a log for {}: {}",
+                                    task_metrics.name,
+                                    error_chain(&e)
+                                );
+                                task_metrics.errors.inc();
+                                task_metrics.dropped.inc();
+                            }
+                        }
+                        task_metrics.queue_depth.dec();
+                    }
+                    QueueMessage::Flush => {
+                        let _ = inner.flush().await;
+                    }
+                    QueueMessage::Close(ack) => {
+                        task_queue.state.lock().unwrap().closed = true;
+                        let _ = ack.send(inner.close().await);
+                        return;
+                    }
+                }
             }
+            // the writer was dropped without closing
+            let _ = inner.close().await;
         });
-        (Self { tx }, handle)
+        (
+            Self {
+                queue,
+                metrics,
+                owned: true,
+            },
+            handle,
+        )
+    }
+
+    /// Another writer to the same queue, which outlives the pipelines using
+    /// the shared writers, e.g. across configuration reloads.
+    pub fn shared(&self) -> Self {
+        self.queue.state.lock().unwrap().writers += 1;
+        Self {
+            queue: self.queue.clone(),
+            metrics: self.metrics.clone(),
+            owned: false,
+        }
+    }
+
+    /// Moves the logs queued in memory to the spill file, ahead of the spilled
+    /// ones, and releases the file, so that the next queue spilling to it sends
+    /// them rather than this one. Returns false if the sink doesn't spill.
+    pub async fn release_spill(&self) -> bool {
+        let (spill, records) = {
+            let mut state = self.queue.state.lock().unwrap();
+            let policy = std::mem::replace(&mut state.policy, Policy::DropNewest);
+            let Policy::Spill(spill) = policy else {
+                state.policy = policy;
+                return false;
+            };
+            let mut records = vec![];
+            for message in std::mem::take(&mut state.messages) {
+                match message {
+                    QueueMessage::Record(record) => {
+                        self.queue.release(&mut state, &record, &self.metrics);
+                        self.metrics.queue_depth.dec();
+                        records.push(record);
+                    }
+                    other => state.messages.push_back(other),
+                }
+            }
+            (spill, records)
+        };
+        self.queue.limits.budget.freed.notify_one();
+        let count = records.len();
+        match spill.release(records).await {
+            Ok(()) => self.metrics.spilled.add(count as i64),
+            Err(e) => {
+                log_error!(
+                    sink: self.metrics.name,
+                    "Dropping {} logs for {}, could not spill them: {}",
+                    count,
+                    self.metrics.name,
+                    error_chain(&e)
+                );
+                self.metrics.errors.inc();
+                self.metrics.dropped.add(count as u64);
+            }
+        }
+        true
+    }
+
+    /// Queues a message regardless of the limit.
+    fn send(&self, message: QueueMessage) -> std::io::Result<()> {
+        let mut state = self.queue.state.lock().unwrap();
+        if state.closed {
+            return Err(std::io::Error::other("Downstream writer is closed"));
+        }
+        // don't pile up flushes behind a slow sink, a busy queue will flush anyway
+        if !matches!(
+            (&message, state.messages.back()),
+            (QueueMessage::Flush, Some(QueueMessage::Flush))
+        ) {
+            state.messages.push_back(message);
+        }
+        self.queue.queued.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for QueueWriter {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.writers -= 1;
+        if state.writers == 0 {
+            self.queue.queued.notify_one();
+        }
     }
 }
 
 #[async_trait]
 impl AsyncLogWriter for QueueWriter {
-    async fn write_logs(&mut self, time: SystemTime, buf: &[u8]) -> std::io::Result<()> {
-        match self.tx.try_send(LogEvent {
-            timestamp: time,
-            message: buf.into(),
-        }) {
-            Ok(_) => Ok(()),
-            Err(Full(_)) => Ok(()), // TODO: data loss, let the caller decide whether to lose it
-            Err(Closed(_)) => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Downstream writer is closed",
-            )),
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        loop {
+            let spill = {
+                let mut state = self.queue.state.lock().unwrap();
+                if state.closed {
+                    return Err(std::io::Error::other("Downstream writer is closed"));
+                }
+                if self.queue.fits(&state, record.size()) && state.spilled() == 0 {
+                    self.queue.push(&mut state, record, &self.metrics);
+                    self.queue.queued.notify_one();
+                    return Ok(());
+                }
+                match &mut state.policy {
+                    Policy::DropNewest => {
+                        log_warn!(
+                            sink: self.metrics.name,
+                            "Queue of {} is full, dropping logs",
+                            self.metrics.name
+                        );
+                        self.metrics.dropped.inc();
+                        return Ok(());
+                    }
+                    Policy::DropOldest => {
+                        log_warn!(
+                            sink: self.metrics.name,
+                            "Queue of {} is full, dropping the oldest logs",
+                            self.metrics.name
+                        );
+                        while !self.queue.fits(&state, record.size())
+                            && self.queue.remove_oldest(&mut state, &self.metrics)
+                        {
+                        }
+                        self.queue.push(&mut state, record, &self.metrics);
+                        self.queue.queued.notify_one();
+                        return Ok(());
+                    }
+                    Policy::Spill(spill) => Some(spill.clone()),
+                    Policy::Block => None,
+                }
+            };
+            let Some(spill) = spill else {
+                self.queue.limits.budget.freed.notified().await;
+                continue;
+            };
+            match spill.push(record.clone()).await {
+                Ok(()) => self.metrics.spilled.inc(),
+                Err(e) => {
+                    log_error!(
+                        sink: self.metrics.name,
+                        "Dropping a log for {}, could not spill it: {}",
+                        self.metrics.name,
+                        error_chain(&e)
+                    );
+                    self.metrics.errors.inc();
+                    self.metrics.dropped.inc();
+                }
+            }
+            self.queue.queued.notify_one();
+            return Ok(());
+        }
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.send(QueueMessage::Flush)
+    }
+
+    /// Waits for the queue to process everything before closing the inner writer.
+    async fn close(&mut self) -> std::io::Result<()> {
+        if !self.owned {
+            return self.flush().await;
         }
+        let (ack_tx, ack_rx) = oneshot::channel();
+        self.send(QueueMessage::Close(ack_tx))?;
+        ack_rx
+            .await
+            .map_err(|_| std::io::Error::other("Downstream writer is closed"))?
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::batch::expect_messages;
     use crate::writer::MockAsyncLogWriter;
     use mockall::predicate::eq;
+    use mockall::Sequence;
     use std::io::Error;
     use std::io::ErrorKind::Other;
+    use std::time::SystemTime;
+    use tokio::sync::Semaphore;
 
     #[tokio::test]
     async fn process_messages() {
@@ -64,24 +613,131 @@ mod tests {
 
         let time = SystemTime::now();
         mock.expect_write_logs()
-            .with(eq(time), eq(b"log1".to_vec()))
+            .with(eq(LogRecord::new(time, b"log1".to_vec())))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_| Ok(()));
         mock.expect_write_logs()
-            .with(eq(time), eq(b"log2".to_vec()))
+            .with(eq(LogRecord::new(time, b"log2".to_vec())))
             .times(1)
-            .returning(|_, _| Ok(()));
+            .returning(|_| Ok(()));
+        mock.expect_close().times(1).returning(|| Ok(()));
 
-        let (mut writer, handle) = QueueWriter::new(mock, 2);
-        writer.write_logs(time, b"log1").await.unwrap();
-        writer.write_logs(time, b"log2").await.unwrap();
+        let (mut writer, handle) =
+            QueueWriter::new(mock, limits(2), Policy::DropNewest, Arc::default());
+        writer
+            .write_logs(&LogRecord::new(time, b"log1".to_vec()))
+            .await
+            .unwrap();
+        writer
+            .write_logs(&LogRecord::new(time, b"log2".to_vec()))
+            .await
+            .unwrap();
         drop(writer);
         handle.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn flush_and_close() {
+        let mut mock = MockAsyncLogWriter::new();
+        let mut seq = Sequence::new();
+
+        let time = SystemTime::now();
+        mock.expect_write_logs()
+            .with(eq(LogRecord::new(time, b"log1".to_vec())))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+        mock.expect_flush()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Ok(()));
+        mock.expect_close()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Err(Error::new(Other, "Error")));
+
+        let (mut writer, handle) =
+            QueueWriter::new(mock, limits(2), Policy::DropNewest, Arc::default());
+        writer
+            .write_logs(&LogRecord::new(time, b"log1".to_vec()))
+            .await
+            .unwrap();
+        writer.flush().await.unwrap();
+        assert!(writer.close().await.is_err());
+        handle.await.unwrap();
+
+        assert!(writer
+            .write_logs(&LogRecord::new(time, b"log2".to_vec()))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn keep_queue_open_after_closing_shared_writer() {
+        let mut mock = MockAsyncLogWriter::new();
+        let mut seq = Sequence::new();
+
+        let time = SystemTime::now();
+        for message in [&b"log1"[..], b"log2"] {
+            mock.expect_write_logs()
+                .with(eq(LogRecord::new(time, message.to_vec())))
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(|_| Ok(()));
+            mock.expect_flush()
+                .times(1)
+                .in_sequence(&mut seq)
+                .returning(|| Ok(()));
+        }
+        mock.expect_close()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Ok(()));
+
+        let (mut writer, handle) =
+            QueueWriter::new(mock, limits(4), Policy::DropNewest, Arc::default());
+        for message in [&b"log1"[..], b"log2"] {
+            let mut shared = writer.shared();
+            shared
+                .write_logs(&LogRecord::new(time, message.to_vec()))
+                .await
+                .unwrap();
+            shared.close().await.unwrap();
+        }
+        writer.close().await.unwrap();
+        handle.await.unwrap();
+    }
+
     #[tokio::test]
     async fn drop_message_after_reaching_limit() {
-        // TODO
+        let mut mock = MockAsyncLogWriter::new();
+
+        let time = SystemTime::now();
+        mock.expect_write_logs()
+            .with(eq(LogRecord::new(time, b"log1".to_vec())))
+            .times(1)
+            .returning(|_| Ok(()));
+        mock.expect_write_logs().times(0);
+        mock.expect_close().times(1).returning(|| Ok(()));
+
+        // the consumer task doesn't run until the test yields
+        let metrics = Arc::new(SinkMetrics::default());
+        let (mut writer, handle) =
+            QueueWriter::new(mock, limits(1), Policy::DropNewest, metrics.clone());
+        for log in [b"log1", b"log2", b"log3"] {
+            writer
+                .write_logs(&LogRecord::new(time, log.to_vec()))
+                .await
+                .unwrap();
+        }
+        assert_eq!(metrics.queue_depth.get(), 1);
+        assert_eq!(metrics.dropped.get(), 2);
+
+        writer.close().await.unwrap();
+        handle.await.unwrap();
+        assert_eq!(metrics.queue_depth.get(), 0);
+        assert_eq!(metrics.dropped.get(), 2);
+        assert_eq!(metrics.lines_written.get(), 1);
     }
 
     #[tokio::test]
@@ -90,17 +746,229 @@ mod tests {
 
         let time = SystemTime::now();
         mock.expect_write_logs()
-            .with(eq(time), eq(b"log1".to_vec()))
+            .with(eq(LogRecord::new(time, b"log1".to_vec())))
             .times(1)
-            .returning(|_, _| Err(Error::new(Other, "Error")));
+            .returning(|_| Err(Error::new(Other, "Error")));
         mock.expect_write_logs()
-            .with(eq(time), eq(b"log1".to_vec()))
+            .with(eq(LogRecord::new(time, b"log1".to_vec())))
             .times(0);
+        mock.expect_close().times(1).returning(|| Ok(()));
 
-        let (mut writer, handle) = QueueWriter::new(mock, 1);
-        writer.write_logs(time, b"log1").await.unwrap();
+        let metrics = Arc::new(SinkMetrics::default());
+        let (mut writer, handle) =
+            QueueWriter::new(mock, limits(1), Policy::DropNewest, metrics.clone());
+        writer
+            .write_logs(&LogRecord::new(time, b"log1".to_vec()))
+            .await
+            .unwrap();
         drop(writer);
 
         handle.await.unwrap();
+        assert_eq!(metrics.errors.get(), 1);
+        assert_eq!(metrics.dropped.get(), 1);
+    }
+
+    /// Waits for the gate to open before each log, as a slow sink does.
+    struct Gated(Arc<Semaphore>, MockAsyncLogWriter);
+
+    #[async_trait]
+    impl AsyncLogWriter for Gated {
+        async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+            let _open = self.0.acquire().await.unwrap();
+            self.1.write_logs(record).await
+        }
+
+        async fn close(&mut self) -> std::io::Result<()> {
+            self.1.close().await
+        }
+    }
+
+    fn limits(items: usize) -> QueueLimits {
+        QueueLimits {
+            items,
+            bytes: None,
+            budget: Arc::default(),
+        }
+    }
+
+    async fn write_all(writer: &mut QueueWriter, messages: &[&[u8]]) {
+        let time = SystemTime::now();
+        for message in messages {
+            writer
+                .write_logs(&LogRecord::new(time, message.to_vec()))
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_message_after_reaching_limit() {
+        let mock = expect_messages(&[b"log3", b"log4"]);
+        let metrics = Arc::new(SinkMetrics::default());
+        let (mut writer, handle) =
+            QueueWriter::new(mock, limits(2), Policy::DropOldest, metrics.clone());
+        write_all(&mut writer, &[b"log1", b"log2", b"log3", b"log4"]).await;
+        assert_eq!(metrics.queue_depth.get(), 2);
+        assert_eq!(metrics.dropped.get(), 2);
+
+        writer.close().await.unwrap();
+        handle.await.unwrap();
+        assert_eq!(metrics.lines_written.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn block_until_the_queue_has_room() {
+        let mock = expect_messages(&[b"log1", b"log2", b"log3"]);
+        let metrics = Arc::new(SinkMetrics::default());
+        let (mut writer, handle) =
+            QueueWriter::new(mock, limits(1), Policy::Block, metrics.clone());
+        write_all(&mut writer, &[b"log1", b"log2", b"log3"]).await;
+
+        writer.close().await.unwrap();
+        handle.await.unwrap();
+        assert_eq!(metrics.dropped.get(), 0);
+        assert_eq!(metrics.lines_written.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn spill_to_disk_after_reaching_limit() {
+        let dir = std::env::temp_dir().join(format!("logup-queue-{}", std::process::id()));
+        let args = QueueArgs {
+            overflow: vec![OverflowRule::parse("spill-to-disk").unwrap()],
+            spill_dir: dir.clone(),
+            max_memory_items: 1,
+            max_memory_bytes: vec![],
+        };
+
+        let gate = Arc::new(Semaphore::new(0));
+        let mock = Gated(
+            gate.clone(),
+            expect_messages(&[b"log1", b"log2", b"log3", b"log4"]),
+        );
+        let metrics = Arc::new(SinkMetrics::default());
+        let policy = args.policy("test").await.unwrap();
+        let (mut writer, handle) = QueueWriter::new(mock, limits(1), policy, metrics.clone());
+        write_all(&mut writer, &[b"log1", b"log2", b"log3"]).await;
+        assert_eq!(metrics.queue_depth.get(), 1);
+        assert_eq!(metrics.spilled.get(), 2);
+        // spilled after the spilled logs to keep the order, though the queue
+        // has room once the sink took the first log
+        tokio::task::yield_now().await;
+        write_all(&mut writer, &[b"log4"]).await;
+        assert_eq!(metrics.spilled.get(), 3);
+
+        gate.add_permits(1);
+        writer.close().await.unwrap();
+        handle.await.unwrap();
+        assert_eq!(metrics.spilled.get(), 0);
+        assert_eq!(metrics.dropped.get(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hand_over_the_spill_file() {
+        let dir = std::env::temp_dir().join(format!("logup-handover-{}", std::process::id()));
+        let args = QueueArgs {
+            overflow: vec![OverflowRule::parse("spill-to-disk").unwrap()],
+            spill_dir: dir.clone(),
+            max_memory_items: 1,
+            max_memory_bytes: vec![],
+        };
+
+        // the removed queue only sends the log it took, the logs in memory go
+        // to the file first
+        let gate = Arc::new(Semaphore::new(0));
+        let metrics = Arc::new(SinkMetrics::default());
+        let policy = args.policy("test").await.unwrap();
+        let mock = Gated(gate.clone(), expect_messages(&[b"log1"]));
+        let (mut writer, handle) = QueueWriter::new(mock, limits(1), policy, metrics.clone());
+        write_all(&mut writer, &[b"log1"]).await;
+        tokio::task::yield_now().await;
+        write_all(&mut writer, &[b"log2", b"log3"]).await;
+        assert!(writer.release_spill().await);
+        assert_eq!(metrics.queue_depth.get(), 1);
+        assert_eq!(metrics.spilled.get(), 2);
+        gate.add_permits(1);
+        writer.close().await.unwrap();
+        handle.await.unwrap();
+
+        let policy = args.policy("test").await.unwrap();
+        let mock = expect_messages(&[b"log2", b"log3"]);
+        let (mut writer, handle) = QueueWriter::new(mock, limits(1), policy, metrics.clone());
+        writer.close().await.unwrap();
+        handle.await.unwrap();
+        assert_eq!(metrics.spilled.get(), 0);
+        assert_eq!(metrics.dropped.get(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn overflow_of_each_sink() {
+        let args = QueueArgs {
+            overflow: ["block", "aws:spill-to-disk", "drop-oldest"]
+                .iter()
+                .map(|rule| OverflowRule::parse(rule).unwrap())
+                .collect(),
+            spill_dir: PathBuf::new(),
+            max_memory_items: 1000,
+            max_memory_bytes: ["1000", "aws:100", "2000"]
+                .iter()
+                .map(|rule| MemoryRule::parse(rule).unwrap())
+                .collect(),
+        };
+        assert_eq!(args.overflow("aws"), Overflow::SpillToDisk);
+        assert_eq!(args.overflow("newrelic"), Overflow::DropOldest);
+        assert!(OverflowRule::parse("aws:drop").is_err());
+        assert_eq!(args.max_memory_bytes(None), Some(2000));
+        assert_eq!(args.max_memory_bytes(Some("aws")), Some(100));
+        assert_eq!(args.max_memory_bytes(Some("newrelic")), None);
+        assert!(MemoryRule::parse("aws:0").is_err());
+    }
+
+    const LARGE: &[u8] = &[b'x'; 10000];
+
+    #[tokio::test]
+    async fn bound_memory_of_each_sink_and_of_all_sinks() {
+        let time = SystemTime::now();
+        let size = LogRecord::new(time, b"log1".to_vec()).size();
+        let budget = Arc::new(MemoryBudget::new(Some(size * 3)));
+
+        // at most two logs in the first queue, three in both
+        let metrics1 = Arc::new(SinkMetrics::default());
+        let limits1 = QueueLimits {
+            items: 10,
+            bytes: Some(size * 2),
+            budget: budget.clone(),
+        };
+        let mock1 = expect_messages(&[b"log1", b"log2"]);
+        let (mut writer1, handle1) =
+            QueueWriter::new(mock1, limits1, Policy::DropNewest, metrics1.clone());
+        let metrics2 = Arc::new(SinkMetrics::default());
+        let limits2 = QueueLimits {
+            items: 10,
+            bytes: None,
+            budget: budget.clone(),
+        };
+        let mock2 = expect_messages(&[b"log4", LARGE]);
+        let (mut writer2, handle2) =
+            QueueWriter::new(mock2, limits2, Policy::DropNewest, metrics2.clone());
+
+        write_all(&mut writer1, &[b"log1", b"log2", b"log3"]).await;
+        write_all(&mut writer2, &[b"log4", b"log5"]).await;
+        assert_eq!(metrics1.queue_bytes.get(), size as i64 * 2);
+        assert_eq!(metrics1.dropped.get(), 1);
+        assert_eq!(metrics2.queue_bytes.get(), size as i64);
+        assert_eq!(metrics2.dropped.get(), 1);
+
+        // a log larger than the budget still goes through an empty queue
+        writer1.close().await.unwrap();
+        handle1.await.unwrap();
+        tokio::task::yield_now().await;
+        write_all(&mut writer2, &[LARGE]).await;
+        assert_eq!(metrics2.dropped.get(), 1);
+
+        writer2.close().await.unwrap();
+        handle2.await.unwrap();
+        assert_eq!(budget.used.load(Relaxed), 0);
     }
 }
@@ -0,0 +1,220 @@
+use crate::record::{LogRecord, Severity};
+use crate::writer::AsyncLogWriter;
+use crate::writer_multi::MultiWriter;
+use async_trait::async_trait;
+use clap::Args;
+use regex::Regex;
+use serde_json::Value;
+
+#[derive(Clone, Debug)]
+enum Condition {
+    Match(Regex),
+    MinLevel(Severity),
+    Source(Regex),
+    Field(String, Regex),
+    /// None of the routes of the other sinks matched.
+    Unmatched,
+}
+
+/// A condition for a sink to receive a record.
+#[derive(Clone, Debug)]
+pub struct Route {
+    sink: String,
+    condition: Condition,
+}
+
+impl Route {
+    /// Parses `SINK:KIND=VALUE`, e.g. `newrelic:min-level=error`, or `SINK:unmatched`.
+    fn parse(route: &str) -> Result<Self, String> {
+        let (sink, condition) = route
+            .split_once(':')
+            .ok_or("expected SINK:KIND=VALUE or SINK:unmatched")?;
+        let regex = |regex: &str| Regex::new(regex).map_err(|e| e.to_string());
+        let condition = match condition.split_once('=') {
+            None if condition == "unmatched" => Condition::Unmatched,
+            None => return Err("expected SINK:KIND=VALUE or SINK:unmatched".to_string()),
+            Some(("match", value)) => Condition::Match(regex(value)?),
+            Some(("min-level", value)) => Condition::MinLevel(
+                Severity::parse(value).ok_or_else(|| format!("unknown level {}", value))?,
+            ),
+            Some(("source", value)) => Condition::Source(regex(value)?),
+            Some(("field", value)) => {
+                let (key, value) = value.split_once('=').ok_or("expected KEY=REGEX")?;
+                Condition::Field(key.to_string(), regex(value)?)
+            }
+            Some((kind, _)) => {
+                return Err(format!(
+                    "unknown route {}, expected match, min-level, source, field or unmatched",
+                    kind
+                ))
+            }
+        };
+        Ok(Self {
+            sink: sink.to_string(),
+            condition,
+        })
+    }
+
+    pub fn sink(&self) -> &str {
+        &self.sink
+    }
+
+    fn matches(&self, record: &LogRecord) -> bool {
+        match &self.condition {
+            Condition::Match(regex) => regex.is_match(&String::from_utf8_lossy(&record.message)),
+            Condition::MinLevel(min) => record.severity.is_some_and(|s| s >= *min),
+            Condition::Source(regex) => regex.is_match(&record.source),
+            Condition::Field(key, regex) => match record.attributes.get(key) {
+                Some(Value::String(value)) => regex.is_match(value),
+                Some(value) => regex.is_match(&value.to_string()),
+                None => false,
+            },
+            Condition::Unmatched => false,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct RouteArgs {
+    #[arg(
+        long,
+        value_name = "SINK:KIND=VALUE",
+        value_parser = Route::parse,
+        help = "Send to the sink only the logs matching any of its routes, sinks without routes receive all the logs. KIND is match with a regex, min-level with a level, source with a regex, or field with KEY=REGEX. SINK:unmatched sends the logs not routed to any other sink"
+    )]
+    pub route: Vec<Route>,
+}
+
+/// The routes of one of the sinks.
+#[derive(Default)]
+struct SinkRoutes {
+    routes: Vec<Route>,
+    unmatched: bool,
+}
+
+/// Sends each record to the sinks whose routes match it.
+pub struct RouterWriter<T: AsyncLogWriter> {
+    inner: MultiWriter<T>,
+    sinks: Vec<SinkRoutes>,
+}
+
+impl<T: AsyncLogWriter> RouterWriter<T> {
+    /// Takes the name and writer of each sink.
+    pub fn new(routes: &[Route], writers: Vec<(String, T)>) -> Self {
+        let sinks = writers
+            .iter()
+            .map(|(name, _)| {
+                let mut sink = SinkRoutes::default();
+                for route in routes.iter().filter(|route| route.sink == *name) {
+                    match route.condition {
+                        Condition::Unmatched => sink.unmatched = true,
+                        _ => sink.routes.push(route.clone()),
+                    }
+                }
+                sink
+            })
+            .collect();
+        let writers = writers.into_iter().map(|(_, writer)| writer).collect();
+        Self {
+            inner: MultiWriter::new(writers),
+            sinks,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: AsyncLogWriter + Send> AsyncLogWriter for RouterWriter<T> {
+    async fn write_logs(&mut self, record: &LogRecord) -> std::io::Result<()> {
+        let matched: Vec<bool> = self
+            .sinks
+            .iter()
+            .map(|sink| sink.routes.iter().any(|route| route.matches(record)))
+            .collect();
+        let any_matched = matched.iter().any(|&m| m);
+        let selected: Vec<bool> = self
+            .sinks
+            .iter()
+            .zip(&matched)
+            .map(|(sink, &matched)| {
+                let routed = !sink.routes.is_empty() || sink.unmatched;
+                !routed || matched || (sink.unmatched && !any_matched)
+            })
+            .collect();
+        self.inner.write_selected(record, |i| selected[i]).await
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::expect_messages;
+    use serde_json::json;
+    use std::time::SystemTime;
+
+    fn record(message: &str, severity: Option<Severity>) -> LogRecord {
+        let mut record = LogRecord::new(SystemTime::now(), message.as_bytes().to_vec());
+        record.severity = severity;
+        record.source = "stdin".to_string();
+        record
+    }
+
+    #[tokio::test]
+    async fn route_records() {
+        let routes: Vec<Route> = [
+            "newrelic:min-level=error",
+            "aws:match=^AUDIT",
+            "aws:field=user=^admin$",
+            "journald:unmatched",
+        ]
+        .iter()
+        .map(|route| Route::parse(route).unwrap())
+        .collect();
+        let writers = vec![
+            ("newrelic".to_string(), expect_messages(&[b"failed\n"])),
+            (
+                "aws".to_string(),
+                expect_messages(&[b"AUDIT login\n", b"sudo\n"]),
+            ),
+            ("journald".to_string(), expect_messages(&[b"started\n"])),
+            (
+                "kinesis".to_string(),
+                expect_messages(&[b"failed\n", b"AUDIT login\n", b"sudo\n", b"started\n"]),
+            ),
+        ];
+        let mut writer = RouterWriter::new(&routes, writers);
+
+        writer
+            .write_logs(&record("failed\n", Some(Severity::Error)))
+            .await
+            .unwrap();
+        writer
+            .write_logs(&record("AUDIT login\n", Some(Severity::Info)))
+            .await
+            .unwrap();
+        let mut sudo = record("sudo\n", None);
+        sudo.attributes.insert("user".to_string(), json!("admin"));
+        writer.write_logs(&sudo).await.unwrap();
+        writer.write_logs(&record("started\n", None)).await.unwrap();
+        writer.close().await.unwrap();
+    }
+
+    #[test]
+    fn parse_routes() {
+        assert_eq!(Route::parse("aws:source=^stdin$").unwrap().sink(), "aws");
+        assert!(Route::parse("aws:source=^stdin$")
+            .unwrap()
+            .matches(&record("x\n", None)));
+        assert!(Route::parse("min-level=error").is_err());
+        assert!(Route::parse("aws:unrouted").is_err());
+        assert!(Route::parse("aws:field=user").is_err());
+        assert!(Route::parse("aws:level=error").is_err());
+    }
+}